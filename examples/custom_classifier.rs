@@ -0,0 +1,65 @@
+//! serve the drain endpoint with a classifier reporting failed payments
+//! of an app to sentry, instead of forking the reporter.
+//!
+//! run with `SENTRY_DSN=... LOGPLEX_TOKEN=... cargo run --example custom_classifier`.
+
+use anyhow::{Context as _, Result};
+use log_reporter::{
+    build_app, Action, BatchState, Config, Destination, Kind, LineClassifier, ParsedLine,
+    SentryMessage,
+};
+use sentry::Level;
+use std::{env, sync::Arc};
+use tokio::net::TcpListener;
+
+#[derive(Debug)]
+struct PaymentFailed;
+
+impl LineClassifier for PaymentFailed {
+    fn name(&self) -> &'static str {
+        "payment_failed"
+    }
+
+    fn classify<'a>(
+        &self,
+        _destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        if line.log.kind != Kind::App {
+            return Ok(None);
+        }
+        let Some(order) = line.log.text.strip_prefix("payment failed for order ") else {
+            return Ok(None);
+        };
+        Ok(Some(vec![Action::Report(SentryMessage::new(
+            Level::Warning,
+            vec!["payment-failed".into()],
+            "payment failed for order %s",
+            vec![order.into()],
+        ))]))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let sentry_dsn = env::var("SENTRY_DSN").context("SENTRY_DSN is missing")?;
+    let logplex_token = env::var("LOGPLEX_TOKEN").context("LOGPLEX_TOKEN is missing")?;
+
+    let sentry_client = Arc::new(sentry::Client::from(sentry_dsn.as_str()));
+    let config = Arc::new(
+        Config::builder()
+            .destination(
+                logplex_token,
+                Destination::new("payments", sentry_client, None),
+            )
+            .classifier(PaymentFailed)
+            .build()?,
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:3000").await?;
+    axum::serve(listener, build_app(config.clone())).await?;
+
+    config.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use crate::reporter::BatchSummary;
+use std::{collections::HashMap, mem, sync::Mutex, time::Duration};
+use tracing::info;
+
+/// totals of the batches of a single logplex token since the last flush.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(crate) struct Rollup {
+    pub(crate) batches: usize,
+    pub(crate) bytes: usize,
+    pub(crate) lines: usize,
+    pub(crate) sentry_events: usize,
+}
+
+/// writes the `access_log` lines for processed batches.
+///
+/// Without an interval we log every batch. With an interval the batches
+/// are summed up per logplex token, and a background task logs one
+/// line per token and interval, so busy drains don't flood our logs.
+#[derive(Debug, Default)]
+pub(crate) struct AccessLog {
+    interval: Option<Duration>,
+    rollups: Mutex<HashMap<String, Rollup>>,
+}
+
+impl AccessLog {
+    pub(crate) fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            rollups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// log a processed batch, or add it to the rollup of its token.
+    pub(crate) fn record(
+        &self,
+        logplex_token: &str,
+        frame_id: Option<&str>,
+        msg_count: Option<usize>,
+        bytes: usize,
+        summary: &BatchSummary,
+        duration: Duration,
+    ) {
+        if self.interval.is_none() {
+            // one line per batch, for request-level accounting.
+            info!(
+                target: "access_log",
+                logplex_token,
+                frame_id,
+                msg_count,
+                bytes,
+                lines = summary.lines,
+                sentry_events = summary.sentry_events,
+                duration_ms = duration.as_millis() as u64,
+                "processed log batch"
+            );
+            return;
+        }
+
+        let mut rollups = self.rollups.lock().unwrap();
+        let rollup = rollups.entry(logplex_token.to_owned()).or_default();
+        rollup.batches += 1;
+        rollup.bytes += bytes;
+        rollup.lines += summary.lines;
+        rollup.sentry_events += summary.sentry_events;
+    }
+
+    /// log one line per token with batches since the last flush,
+    /// and start over.
+    pub(crate) fn flush(&self) {
+        for (logplex_token, rollup) in self.take() {
+            info!(
+                target: "access_log",
+                logplex_token,
+                batches = rollup.batches,
+                bytes = rollup.bytes,
+                lines = rollup.lines,
+                sentry_events = rollup.sentry_events,
+                "processed log batches"
+            );
+        }
+    }
+
+    fn take(&self) -> HashMap<String, Rollup> {
+        mem::take(&mut *self.rollups.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(lines: usize, sentry_events: usize) -> BatchSummary {
+        BatchSummary {
+            lines,
+            sentry_events,
+        }
+    }
+
+    #[test]
+    fn test_rollup_per_token() {
+        let access_log = AccessLog::new(Some(Duration::from_secs(10)));
+
+        access_log.record("d.1", None, Some(2), 100, &summary(2, 1), Duration::ZERO);
+        access_log.record("d.1", None, Some(3), 200, &summary(3, 0), Duration::ZERO);
+        access_log.record("d.2", None, None, 50, &summary(1, 0), Duration::ZERO);
+
+        let rollups = access_log.take();
+        assert_eq!(
+            rollups["d.1"],
+            Rollup {
+                batches: 2,
+                bytes: 300,
+                lines: 5,
+                sentry_events: 1,
+            }
+        );
+        assert_eq!(rollups["d.2"].batches, 1);
+
+        // flushed rollups start over
+        assert!(access_log.take().is_empty());
+    }
+
+    #[test]
+    fn test_no_rollup_without_interval() {
+        let access_log = AccessLog::new(None);
+
+        access_log.record("d.1", None, Some(2), 100, &summary(2, 1), Duration::ZERO);
+
+        assert!(access_log.take().is_empty());
+    }
+}
@@ -1,39 +1,507 @@
-use crate::{config::Config, log_parser::ScalingEvent, metrics::generate_librato_scaling_metrics};
+use crate::{
+    config::{Config, ConfigHandle, Destination},
+    forward,
+    http_client::{self, REQUEST_ID},
+    log_parser::{OwnedScalingEvent, ScalingEvent},
+    metrics::{
+        generate_librato_alive_metric, generate_librato_destinations_loaded_metric,
+        generate_librato_scaling_metrics,
+    },
+};
+use anyhow::Result;
 use chrono::Local;
-use std::{sync::Arc, time::Duration};
-use tokio::time::sleep;
-use tracing::debug;
+use std::{collections::HashMap, iter, sync::Arc, time::Duration};
+use tokio::time::{interval_at, sleep_until, Instant};
+use tracing::{debug, warn};
+
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how often spilled measurements are queued for librato again.
+pub(crate) const SPILLOVER_REPLAY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// spilled measurements queued per destination and tick, so a recovered
+/// librato account isn't flooded.
+const REPLAYED_MEASUREMENTS_PER_TICK: usize = 1000;
+
+/// scaling events are re-sent at the normal interval for this long
+/// after they changed, see `resend_backoff_interval`.
+const FULL_RESEND_CADENCE: Duration = Duration::from_secs(60);
+
+/// when to re-send the scaling events of a destination next.
+#[derive(Debug)]
+struct ResendState {
+    next_resend: Instant,
+    /// the last change of the dyno counts we know of.
+    last_change: Instant,
+}
+
+impl ResendState {
+    fn new(now: Instant, interval: Duration) -> Self {
+        Self {
+            next_resend: now + interval,
+            last_change: now,
+        }
+    }
+
+    /// `true` when the scaling events of the destination are due at `now`,
+    /// then schedules the next resend. Changed dyno counts are re-sent
+    /// after `interval`, even when we backed off.
+    fn is_due(&mut self, destination: &Destination, interval: Duration, now: Instant) -> bool {
+        let last_change = *destination.last_scaling_change.lock().unwrap();
+        if let Some(last_change) = last_change.map(Instant::from_std) {
+            if last_change > self.last_change {
+                self.last_change = last_change;
+                self.next_resend = self.next_resend.min(last_change + interval);
+            }
+        }
+
+        if self.next_resend > now {
+            return false;
+        }
+        self.next_resend = now
+            + next_resend_interval(
+                interval,
+                destination.settings.resend_backoff_interval,
+                now - self.last_change,
+            );
+        true
+    }
+}
 
 /// when sending scaling events as gauge.
 /// we have an issue where metrics would report the dyno count as
 /// "not reported" or zero between scaling events.
 ///
 /// So we just store the last reported values and then regularly
-/// re-send them.
+/// re-send them, per destination in its own interval.
+/// With the global interval we send a heartbeat gauge to every destination,
+/// so alerts can be based on the absence of the metric, together with
+/// the amount of loaded destinations.
+///
+/// Muted destinations get neither.
+///
 /// due to how tokio works this spawned task won't block the server shutdown.
-pub(crate) async fn resend_scaling_events(config: Arc<Config>) {
+pub(crate) async fn resend_scaling_events(handle: Arc<ConfigHandle>) {
+    let mut resends = Resends::new(&handle.current(), Instant::now());
     loop {
-        sleep(Duration::from_secs(10)).await;
+        sleep_until(resends.next_wakeup()).await;
 
-        for (_, destination) in config.destinations.iter() {
-            let last_scaling_events = destination.last_scaling_events.lock().unwrap();
+        let config = handle.current();
+        let now = Instant::now();
+        resends.tick(&config, now);
+        resends.schedule(&config, now);
+    }
+}
 
-            let Some(events) = &*last_scaling_events else {
-                continue;
-            };
+/// the next heartbeat and the resend state per logplex token,
+/// see [`resend_scaling_events`].
+#[derive(Debug)]
+struct Resends {
+    next_heartbeat: Instant,
+    destinations: HashMap<String, ResendState>,
+}
 
-            let Some(ref librato_client) = destination.librato_client else {
+impl Resends {
+    fn new(config: &Config, now: Instant) -> Self {
+        let mut resends = Self {
+            next_heartbeat: now + config.resend_interval,
+            destinations: HashMap::new(),
+        };
+        resends.schedule(config, now);
+        resends
+    }
+
+    /// destinations added by a reload are scheduled from `now`,
+    /// removed ones are forgotten.
+    fn schedule(&mut self, config: &Config, now: Instant) {
+        self.destinations
+            .retain(|token, _| config.destinations.contains_key(token));
+        for (token, destination) in enabled_destinations(config) {
+            self.destinations
+                .entry(token.clone())
+                .or_insert_with(|| ResendState::new(now, resend_interval(config, destination)));
+        }
+    }
+
+    fn next_wakeup(&self) -> Instant {
+        self.destinations
+            .values()
+            .map(|state| state.next_resend)
+            .chain(iter::once(self.next_heartbeat))
+            .min()
+            .expect("the heartbeat is always scheduled")
+    }
+
+    /// send the heartbeat and the scaling events that are due at `now`.
+    fn tick(&mut self, config: &Config, now: Instant) {
+        if self.next_heartbeat <= now {
+            for (_, destination) in enabled_destinations(config) {
+                if destination.has_metric_clients() {
+                    let timestamp = Local::now().fixed_offset();
+                    let measurements = [
+                        generate_librato_alive_metric(&timestamp),
+                        generate_librato_destinations_loaded_metric(
+                            &timestamp,
+                            config.destinations.len(),
+                        ),
+                    ];
+                    for measurement in measurements.into_iter().flatten() {
+                        destination.add_measurement(measurement);
+                    }
+                }
+            }
+            self.next_heartbeat = now + config.resend_interval;
+        }
+
+        for (token, destination) in enabled_destinations(config) {
+            let Some(state) = self.destinations.get_mut(token) else {
                 continue;
             };
+            if !state.is_due(destination, resend_interval(config, destination), now) {
+                continue;
+            }
 
-            let events: Vec<ScalingEvent<'_>> = events.iter().map(Into::into).collect();
-            debug!("resending scaling metrics");
+            // a snapshot, so live processing isn't blocked while we generate
+            // and send the metrics.
+            let events = destination.last_scaling_events.lock().unwrap().clone();
+            if let Some(ref events) = events {
+                resend_last_scaling_events(destination, events);
+            }
+        }
+    }
+}
+
+fn resend_interval(config: &Config, destination: &Destination) -> Duration {
+    destination
+        .settings
+        .resend_interval
+        .unwrap_or(config.resend_interval)
+}
+
+fn enabled_destinations(config: &Config) -> impl Iterator<Item = (&String, &Arc<Destination>)> {
+    config
+        .destinations
+        .iter()
+        .filter(|(_, destination)| destination.settings.enabled)
+}
+
+/// the time until the next resend. Scaling events that didn't change
+/// for `FULL_RESEND_CADENCE` are re-sent at the backoff interval, if set.
+fn next_resend_interval(
+    interval: Duration,
+    backoff_interval: Option<Duration>,
+    unchanged_for: Duration,
+) -> Duration {
+    match backoff_interval {
+        Some(backoff_interval) if unchanged_for >= FULL_RESEND_CADENCE => {
+            backoff_interval.max(interval)
+        }
+        _ => interval,
+    }
+}
+
+/// log the summed up access log lines, see [`crate::access_log::AccessLog`].
+/// The last rollups are flushed on shutdown.
+pub(crate) async fn flush_access_log(handle: Arc<ConfigHandle>, interval: Duration) {
+    let mut ticker = interval_at(Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        handle.current().access_log.flush();
+    }
+}
 
-            for measurement in
-                generate_librato_scaling_metrics(&Local::now().fixed_offset(), &events)
-            {
-                librato_client.add_measurement(measurement);
+/// queue the measurements we kept while librato was backed off, once it
+/// recovered, see `spillover_measurements`. The rest is queued on shutdown.
+pub(crate) async fn replay_spilled_measurements(handle: Arc<ConfigHandle>) {
+    let mut ticker = interval_at(
+        Instant::now() + SPILLOVER_REPLAY_INTERVAL,
+        SPILLOVER_REPLAY_INTERVAL,
+    );
+    loop {
+        ticker.tick().await;
+        for destination in handle.current().destinations.values() {
+            destination.replay_spilled_measurements(REPLAYED_MEASUREMENTS_PER_TICK);
+        }
+    }
+}
+
+/// send the lines queued for forwarding regularly, otherwise a quiet drain
+/// only sends them with its next line.
+pub(crate) async fn flush_forward_queues(handle: Arc<ConfigHandle>) {
+    let mut ticker = interval_at(
+        Instant::now() + forward::FLUSH_INTERVAL,
+        forward::FLUSH_INTERVAL,
+    );
+    loop {
+        ticker.tick().await;
+        for destination in handle.current().destinations.values() {
+            if let Some(ref forward_client) = destination.forward_client {
+                forward_client.flush();
+            }
+        }
+    }
+}
+
+/// ping an external healthcheck URL, like healthchecks.io, so it alerts
+/// when the reporter stops. Failed pings are only logged.
+pub(crate) async fn ping_heartbeat_url(url: String, interval: Duration) {
+    let http_client = http_client::builder()
+        .timeout(HEARTBEAT_TIMEOUT)
+        .build()
+        .expect("could not build HTTP client");
+
+    let mut ticker = interval_at(Instant::now(), interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = ping(&http_client, &url).await {
+            // the URL is the secret of the check, so we don't log it.
+            warn!(?err, "heartbeat ping failed");
+        }
+    }
+}
+
+async fn ping(http_client: &reqwest::Client, url: &str) -> Result<()> {
+    http_client
+        .get(url)
+        .header(&REQUEST_ID, http_client::new_request_id())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn resend_last_scaling_events(destination: &Destination, events: &[OwnedScalingEvent]) {
+    if !destination.has_metric_clients() {
+        return;
+    }
+
+    let scaling_events: Vec<ScalingEvent<'_>> = events.iter().map(Into::into).collect();
+    debug!(destination = destination.name, "resending scaling metrics");
+
+    let measure_time = destination.next_resend_scaling_time(Local::now().fixed_offset());
+    for measurement in generate_librato_scaling_metrics(
+        &measure_time,
+        &scaling_events,
+        destination.settings.scaling_total,
+        destination.settings.dyno_count_grouping,
+        destination.settings.metric_name_case,
+        // the user only belongs to the original scaling event.
+        None,
+    ) {
+        destination.add_measurement(measurement);
+    }
+    destination.record_scaling_resend(measure_time, events);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::TestSink;
+    use std::{sync::mpsc, thread};
+    use test_case::test_case;
+
+    #[test]
+    fn test_concurrent_scaling_updates_and_resends() {
+        let test_sink = Arc::new(TestSink::default());
+        let destination = Arc::new(
+            Destination::new(
+                "test",
+                Arc::new(sentry::Client::from(sentry::ClientOptions::default())),
+                None,
+            )
+            .with_test_sink(test_sink.clone()),
+        );
+
+        let (done, finished) = mpsc::channel();
+        thread::spawn({
+            let destination = destination.clone();
+            move || {
+                thread::scope(|scope| {
+                    for _ in 0..4 {
+                        scope.spawn(|| {
+                            for count in 0..1000 {
+                                destination.update_scaling_events(
+                                    vec![OwnedScalingEvent {
+                                        proc: "web".into(),
+                                        count,
+                                        size: "Standard-1X".into(),
+                                    }],
+                                    Local::now().fixed_offset(),
+                                );
+                            }
+                        });
+                        scope.spawn(|| {
+                            for _ in 0..1000 {
+                                let events =
+                                    destination.last_scaling_events.lock().unwrap().clone();
+                                if let Some(events) = events {
+                                    resend_last_scaling_events(&destination, &events);
+                                }
+                            }
+                        });
+                    }
+                });
+                done.send(()).unwrap();
+            }
+        });
+
+        finished
+            .recv_timeout(Duration::from_secs(30))
+            .expect("concurrent updates and resends deadlocked");
+        assert!(destination.last_scaling_events.lock().unwrap().is_some());
+        assert!(!test_sink.fetch_and_clear().is_empty());
+    }
+
+    #[test_case(200, true; "success")]
+    #[test_case(500, false; "error status")]
+    #[tokio::test]
+    async fn test_ping(status: usize, expected: bool) {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/uuid")
+            .match_header("user-agent", http_client::user_agent().as_str())
+            .match_header("x-request-id", mockito::Matcher::Any)
+            .with_status(status)
+            .create_async()
+            .await;
+
+        let result = ping(
+            &http_client::builder().build().unwrap(),
+            &format!("{}/uuid", server.url()),
+        )
+        .await;
+
+        mock.assert_async().await;
+        assert_eq!(result.is_ok(), expected);
+    }
+
+    #[test]
+    fn test_destinations_are_resent_at_their_own_interval() {
+        let destination = |settings: &str| {
+            Destination::new(
+                "test",
+                Arc::new(sentry::Client::from(sentry::ClientOptions::default())),
+                None,
+            )
+            .with_settings(settings.parse().unwrap())
+        };
+        let fast = destination("resend_interval=5");
+        let slow = destination("resend_interval=30");
+        let interval = |destination: &Destination| destination.settings.resend_interval.unwrap();
+
+        let started = Instant::now();
+        let mut fast_state = ResendState::new(started, interval(&fast));
+        let mut slow_state = ResendState::new(started, interval(&slow));
+
+        let mut fast_resends = 0;
+        let mut slow_resends = 0;
+        for second in 1..=60 {
+            let now = started + Duration::from_secs(second);
+            if fast_state.is_due(&fast, interval(&fast), now) {
+                fast_resends += 1;
+            }
+            if slow_state.is_due(&slow, interval(&slow), now) {
+                slow_resends += 1;
             }
         }
+
+        assert_eq!(fast_resends, 12);
+        assert_eq!(slow_resends, 2);
+        assert_eq!(fast_state.next_resend, started + Duration::from_secs(65));
+        assert_eq!(slow_state.next_resend, started + Duration::from_secs(90));
+    }
+
+    #[test]
+    fn test_muted_destinations_are_not_resent() {
+        let destination = |settings: &str, test_sink: &Arc<TestSink>| {
+            let destination = Destination::new(
+                "test",
+                Arc::new(sentry::Client::from(sentry::ClientOptions::default())),
+                None,
+            )
+            .with_settings(settings.parse().unwrap())
+            .with_test_sink(test_sink.clone());
+            destination.update_scaling_events(
+                vec![OwnedScalingEvent {
+                    proc: "web".into(),
+                    count: 2,
+                    size: "Standard-1X".into(),
+                }],
+                Local::now().fixed_offset(),
+            );
+            destination
+        };
+        let enabled_sink = Arc::new(TestSink::default());
+        let muted_sink = Arc::new(TestSink::default());
+        let interval = Duration::from_secs(10);
+        let config = Config::builder()
+            .resend_interval(interval)
+            .destination("enabled_token", destination("", &enabled_sink))
+            .destination("muted_token", destination("enabled=false", &muted_sink))
+            .build()
+            .unwrap();
+
+        let started = Instant::now();
+        let mut resends = Resends::new(&config, started);
+        resends.tick(&config, started + interval);
+
+        let measurements = enabled_sink.fetch_and_clear();
+        assert!(measurements.iter().any(|m| m.name == "logreporter.alive"));
+        assert!(measurements.iter().any(|m| m.name == "destinations_loaded"));
+        assert!(measurements
+            .iter()
+            .any(|m| m.name.starts_with("dyno_count")));
+        assert!(muted_sink.fetch_and_clear().is_empty());
+    }
+
+    #[test]
+    fn test_scaling_change_ends_backoff() {
+        let destination = Destination::new(
+            "test",
+            Arc::new(sentry::Client::from(sentry::ClientOptions::default())),
+            None,
+        )
+        .with_settings("resend_backoff_interval=300".parse().unwrap());
+        let interval = Duration::from_secs(10);
+        let started = Instant::now();
+        let mut state = ResendState::new(started, interval);
+
+        // unchanged for long enough to back off
+        let now = started + FULL_RESEND_CADENCE;
+        assert!(state.is_due(&destination, interval, now));
+        assert_eq!(state.next_resend, now + Duration::from_secs(300));
+        assert!(!state.is_due(&destination, interval, now + interval));
+
+        destination.update_scaling_events(
+            vec![OwnedScalingEvent {
+                proc: "web".into(),
+                count: 2,
+                size: "Standard-1X".into(),
+            }],
+            Local::now().fixed_offset(),
+        );
+        let changed = Instant::from_std(destination.last_scaling_change.lock().unwrap().unwrap());
+        assert!(!state.is_due(&destination, interval, changed));
+        assert_eq!(state.next_resend, changed + interval);
+
+        // back at the full cadence after the change
+        let now = changed + interval;
+        assert!(state.is_due(&destination, interval, now));
+        assert_eq!(state.next_resend, now + interval);
+    }
+
+    #[test_case(None, 600, 10; "without backoff")]
+    #[test_case(Some(60), 30, 10; "recently changed")]
+    #[test_case(Some(60), 60, 60; "unchanged")]
+    #[test_case(Some(5), 600, 10; "backoff shorter than interval")]
+    fn test_next_resend_interval(backoff: Option<u64>, unchanged_secs: u64, expected_secs: u64) {
+        assert_eq!(
+            next_resend_interval(
+                Duration::from_secs(10),
+                backoff.map(Duration::from_secs),
+                Duration::from_secs(unchanged_secs),
+            ),
+            Duration::from_secs(expected_secs)
+        );
     }
 }
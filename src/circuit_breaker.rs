@@ -0,0 +1,110 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default)]
+struct State {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// stops calls to a failing dependency for a while.
+///
+/// After `failure_threshold` consecutive failures the circuit opens for
+/// `cooldown`. After that the next call is let through to test recovery,
+/// a single failure then opens the circuit again, a success closes it.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// `true` while calls should be skipped.
+    pub(crate) fn is_open(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .opened_at
+            .is_some_and(|opened_at| opened_at.elapsed() < self.cooldown)
+    }
+
+    /// `true` when the last call succeeded, unlike `!is_open()`, which is
+    /// also `true` while testing recovery after the cooldown.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().consecutive_failures == 0
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.opened_at = None;
+    }
+
+    pub(crate) fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        assert!(!breaker.is_open());
+
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+
+        breaker.record_failure();
+        assert!(breaker.is_open());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn test_half_open_after_cooldown() {
+        let breaker = CircuitBreaker::new(2, Duration::from_millis(10));
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!breaker.is_open());
+        assert!(!breaker.is_closed());
+
+        // a single failure while half-open opens the circuit again
+        breaker.record_failure();
+        assert!(breaker.is_open());
+
+        std::thread::sleep(Duration::from_millis(20));
+        breaker.record_success();
+        assert!(breaker.is_closed());
+        breaker.record_failure();
+        assert!(!breaker.is_open());
+    }
+}
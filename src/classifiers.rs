@@ -0,0 +1,832 @@
+use crate::{
+    config::Destination,
+    librato::Measurement,
+    log_parser::{
+        looks_like_logfmt, parse_dyno_error_code, parse_key_value_pairs, parse_process_start,
+        parse_process_stop, parse_scaling_event, parse_state_change, Kind, LogLine, LogMap,
+        RouterLog,
+    },
+    metrics::{
+        generate_librato_addon_metrics, generate_librato_boot_duration_metric,
+        generate_librato_heartbeat_metric, generate_librato_insecure_request_metric,
+        generate_librato_lifecycle_metric, generate_librato_response_bytes_metric,
+        generate_librato_router_error_metric, generate_librato_router_warning_metric,
+        generate_librato_sample_metrics, generate_librato_scaling_conflict_metric,
+        generate_librato_scaling_metrics, generate_librato_timeout_service_metrics,
+    },
+    reporter::{
+        forwarded_json_app_log, generate_app_error_message, generate_dyno_error_message,
+        generate_insecure_request_message, generate_json_app_log_message,
+        generate_request_timeout_message, generate_router_error_message,
+        generate_router_warning_message, generate_unclassified_message, matching_app_pattern,
+        route_from_router_log, SentryMessage,
+    },
+    routes,
+};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, FixedOffset};
+use std::{cell::OnceCell, collections::HashMap, fmt::Debug, sync::Arc};
+use tracing::{debug, info, warn};
+
+/// what should happen because of a log line.
+#[derive(Debug)]
+pub enum Action {
+    /// send the message to the sentry project of the destination.
+    Report(SentryMessage),
+    /// send the message to the shared sentry project for unclassified lines.
+    ReportUnclassified(SentryMessage),
+    /// add the measurement to the metric clients of the destination.
+    Measure(Measurement),
+}
+
+/// a parsed log line, with its logfmt pairs parsed on first use.
+pub struct ParsedLine<'a> {
+    pub log: LogLine<'a>,
+    pairs: OnceCell<LogMap<'a>>,
+}
+
+impl<'a> ParsedLine<'a> {
+    pub fn new(log: LogLine<'a>) -> Self {
+        Self {
+            log,
+            pairs: OnceCell::new(),
+        }
+    }
+
+    /// the logfmt pairs of the text, errors when the text isn't logfmt.
+    pub fn pairs(&self) -> Result<&LogMap<'a>> {
+        if let Some(pairs) = self.pairs.get() {
+            return Ok(pairs);
+        }
+        if !looks_like_logfmt(self.log.text) {
+            debug!(text = self.log.text, "not a logfmt line");
+            bail!("not a logfmt line");
+        }
+        // only the error kind is kept, copying the unparsed rest of the
+        // text into every error is expensive for long lines.
+        let (_, pairs) = parse_key_value_pairs(self.log.text).map_err(|err| {
+            debug!(text = self.log.text, "could not parse key value pairs");
+            anyhow!(
+                "could not parse key value pairs: {}",
+                err.map(|err| err.code)
+            )
+        })?;
+        Ok(self.pairs.get_or_init(|| pairs))
+    }
+}
+
+/// state shared by the classifiers over a single batch.
+#[derive(Debug, Default)]
+pub struct BatchState<'a> {
+    /// dyno -> when its process was started, to measure the boot duration.
+    boot_starts: HashMap<&'a str, DateTime<FixedOffset>>,
+}
+
+/// detects one kind of log line and decides what to do with it.
+///
+/// Classifiers are tried in order, the first one returning `Some`
+/// handles the line, even without actions. Errors abort the batch.
+/// Services using this crate as a library add their own classifiers with
+/// [`crate::ConfigBuilder::classifier`].
+pub trait LineClassifier: Debug + Send + Sync {
+    /// used in `disabled_classifiers`.
+    fn name(&self) -> &'static str;
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>>;
+}
+
+/// the names of the [`default_classifiers`], for validating the settings
+/// without building them.
+pub(crate) const CLASSIFIER_NAMES: &[&str] = &[
+    "heartbeat",
+    "router",
+    "dyno_error",
+    "lifecycle",
+    "sample_metrics",
+    "addon_metrics",
+    "scaling",
+    "json_app_log",
+    "app_pattern",
+    "unclassified",
+];
+
+/// the classifiers every destination uses, in order.
+pub(crate) fn default_classifiers() -> Vec<Arc<dyn LineClassifier>> {
+    vec![
+        Arc::new(Heartbeat),
+        Arc::new(Router),
+        Arc::new(DynoError),
+        Arc::new(Lifecycle),
+        Arc::new(SampleMetrics),
+        Arc::new(AddonMetrics),
+        Arc::new(Scaling),
+        Arc::new(JsonAppLog),
+        Arc::new(AppPattern),
+        Arc::new(Unclassified),
+    ]
+}
+
+/// empty lines, quiet dynos still send them. See `dyno_heartbeats`.
+#[derive(Debug)]
+pub(crate) struct Heartbeat;
+
+impl LineClassifier for Heartbeat {
+    fn name(&self) -> &'static str {
+        "heartbeat"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let log = &line.log;
+        if !log.text.trim().is_empty() {
+            return Ok(None);
+        }
+        if !destination.settings.dyno_heartbeats {
+            return Ok(Some(Vec::new()));
+        }
+        Ok(Some(
+            generate_librato_heartbeat_metric(&log.timestamp, log.source)
+                .map(Action::Measure)
+                .into_iter()
+                .collect(),
+        ))
+    }
+}
+
+/// heroku router lines, for request metrics, timeouts and router errors.
+#[derive(Debug)]
+pub(crate) struct Router;
+
+impl LineClassifier for Router {
+    fn name(&self) -> &'static str {
+        "router"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let log = &line.log;
+        if !matches!(log.kind, Kind::Heroku) || log.source != "router" {
+            return Ok(None);
+        }
+
+        let map = line.pairs()?;
+        debug!(?map, "got router log");
+
+        let router_log = match RouterLog::try_from(map) {
+            Ok(router_log) => router_log,
+            Err(err) => {
+                warn!(?log, "{}", err);
+                return Ok(Some(Vec::new()));
+            }
+        };
+
+        let settings = &destination.settings;
+        let templaters = routes::templaters(settings.number_min_digits);
+        let trailing_slash = settings.trailing_slash;
+        let mut actions = Vec::new();
+
+        if let (Some(route), Some(bytes)) = (
+            route_from_router_log(&router_log, &templaters, trailing_slash),
+            router_log.bytes,
+        ) {
+            actions.extend(
+                generate_librato_response_bytes_metric(&log.timestamp, &route, bytes)
+                    .map(Action::Measure),
+            );
+        }
+
+        if router_log.protocol == Some("http") {
+            if settings.insecure_request_metrics {
+                actions.extend(
+                    generate_librato_insecure_request_metric(
+                        &log.timestamp,
+                        router_log.host.unwrap_or("unknown"),
+                    )
+                    .map(Action::Measure),
+                );
+            }
+            if settings.sentry_insecure_requests {
+                if let Some(msg) =
+                    generate_insecure_request_message(log, &router_log, &templaters, trailing_slash)
+                {
+                    actions.push(Action::Report(msg));
+                }
+            }
+        }
+
+        match router_log.at {
+            at if settings
+                .router_error_markers
+                .iter()
+                .any(|marker| marker == at) =>
+            {
+                let Some(code) = router_log.code else {
+                    warn!(?log, "missing `code` in router `error` log line");
+                    return Ok(Some(actions));
+                };
+
+                actions.extend(
+                    generate_librato_router_error_metric(&log.timestamp, code).map(Action::Measure),
+                );
+
+                if code == "H12" {
+                    if let (Some(route), Some(service_ms)) = (
+                        route_from_router_log(&router_log, &templaters, trailing_slash),
+                        router_log.service_ms,
+                    ) {
+                        actions.extend(
+                            generate_librato_timeout_service_metrics(
+                                &log.timestamp,
+                                &route,
+                                router_log.method,
+                                service_ms,
+                            )
+                            .into_iter()
+                            .map(Action::Measure),
+                        );
+                    }
+
+                    if let Some(msg) = generate_request_timeout_message(
+                        log,
+                        &router_log,
+                        &templaters,
+                        trailing_slash,
+                    ) {
+                        actions.push(Action::Report(msg));
+                    }
+                } else if settings
+                    .sentry_router_error_codes
+                    .iter()
+                    .any(|listed| listed == code)
+                {
+                    if let Some(msg) = generate_router_error_message(
+                        code,
+                        log,
+                        &router_log,
+                        &templaters,
+                        trailing_slash,
+                    ) {
+                        actions.push(Action::Report(msg));
+                    }
+                }
+            }
+            "warning" => {
+                actions.extend(
+                    generate_librato_router_warning_metric(
+                        &log.timestamp,
+                        router_log.code.unwrap_or("unknown"),
+                    )
+                    .map(Action::Measure),
+                );
+
+                if settings.sentry_router_warnings {
+                    if let Some(msg) = generate_router_warning_message(
+                        log,
+                        &router_log,
+                        &templaters,
+                        trailing_slash,
+                    ) {
+                        actions.push(Action::Report(msg));
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(Some(actions))
+    }
+}
+
+/// dyno errors like `Error R14 (Memory quota exceeded)`.
+#[derive(Debug)]
+pub(crate) struct DynoError;
+
+impl LineClassifier for DynoError {
+    fn name(&self) -> &'static str {
+        "dyno_error"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let Ok((_, (code, name))) = parse_dyno_error_code(line.log.text) else {
+            return Ok(None);
+        };
+        let level = destination.settings.dyno_error_level(code);
+        Ok(Some(
+            generate_dyno_error_message(code, name, level, &line.log)
+                .map(Action::Report)
+                .into_iter()
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum LifecycleEvent {
+    Starting,
+    Up,
+    Stopping,
+}
+
+/// dyno lifecycle events from heroku. Starts & stops are sent as
+/// deploy & restart markers, and we measure the boot time between
+/// starting and up.
+fn lifecycle_event(logline: &LogLine) -> Option<LifecycleEvent> {
+    if !matches!(logline.kind, Kind::Heroku) {
+        return None;
+    }
+    if parse_process_start(logline.text).is_ok() {
+        return Some(LifecycleEvent::Starting);
+    }
+    if parse_process_stop(logline.text).is_ok() {
+        return Some(LifecycleEvent::Stopping);
+    }
+    match parse_state_change(logline.text) {
+        Ok((_, ("starting", "up"))) => Some(LifecycleEvent::Up),
+        _ => None,
+    }
+}
+
+/// dyno starts, stops and the boot duration.
+#[derive(Debug)]
+pub(crate) struct Lifecycle;
+
+impl LineClassifier for Lifecycle {
+    fn name(&self) -> &'static str {
+        "lifecycle"
+    }
+
+    fn classify<'a>(
+        &self,
+        _destination: &Destination,
+        line: &ParsedLine<'a>,
+        batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let log = &line.log;
+        let Some(lifecycle_event) = lifecycle_event(log) else {
+            return Ok(None);
+        };
+
+        let measurement = match lifecycle_event {
+            LifecycleEvent::Starting => {
+                batch.boot_starts.insert(log.source, log.timestamp);
+                generate_librato_lifecycle_metric(&log.timestamp, log.source, "starting")
+            }
+            LifecycleEvent::Stopping => {
+                generate_librato_lifecycle_metric(&log.timestamp, log.source, "stopping")
+            }
+            // only inside a single batch.
+            LifecycleEvent::Up => batch.boot_starts.remove(log.source).and_then(|started| {
+                generate_librato_boot_duration_metric(
+                    &log.timestamp,
+                    log.source,
+                    log.timestamp
+                        .signed_duration_since(started)
+                        .num_milliseconds(),
+                )
+            }),
+        };
+        Ok(Some(measurement.map(Action::Measure).into_iter().collect()))
+    }
+}
+
+/// heroku runtime metrics, like `sample#memory_rss=158.27MB`.
+/// See `sample_metrics`.
+#[derive(Debug)]
+pub(crate) struct SampleMetrics;
+
+impl LineClassifier for SampleMetrics {
+    fn name(&self) -> &'static str {
+        "sample_metrics"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let log = &line.log;
+        if !matches!(log.kind, Kind::Heroku)
+            || !destination.settings.sample_metrics
+            || !log.text.contains("sample#")
+            || !destination.has_metric_clients()
+        {
+            return Ok(None);
+        }
+
+        // a text mentioning `sample#` isn't a runtime metric line,
+        // the next classifiers get a chance.
+        let Ok(map) = line.pairs() else {
+            return Ok(None);
+        };
+        let source = map.get("source").copied().unwrap_or(log.source);
+        // all samples of a line are kept or dropped together.
+        if !destination
+            .metric_sampler
+            .sample(source, destination.settings.metric_sample_rate)
+        {
+            destination.counters.increment("sample_metrics_sampled_out");
+            return Ok(Some(Vec::new()));
+        }
+
+        Ok(Some(
+            generate_librato_sample_metrics(
+                &log.timestamp,
+                source,
+                map,
+                destination.settings.sample_key_separator,
+            )
+            .into_iter()
+            .map(Action::Measure)
+            .collect(),
+        ))
+    }
+}
+
+/// addon metrics, like `sample#db_size=4709591bytes` from heroku postgres.
+/// See `addon_sources`.
+#[derive(Debug)]
+pub(crate) struct AddonMetrics;
+
+impl LineClassifier for AddonMetrics {
+    fn name(&self) -> &'static str {
+        "addon_metrics"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let log = &line.log;
+        if !matches!(log.kind, Kind::App)
+            || !destination
+                .settings
+                .addon_sources
+                .iter()
+                .any(|addon| addon == log.source)
+            || !log.text.contains("sample#")
+            || !destination.has_metric_clients()
+        {
+            return Ok(None);
+        }
+
+        // not logfmt, so not from the addon itself.
+        let Ok(map) = line.pairs() else {
+            return Ok(None);
+        };
+        // the attachment, like `DATABASE`.
+        let source = map.get("source").copied().unwrap_or(log.source);
+
+        Ok(Some(
+            generate_librato_addon_metrics(&log.timestamp, log.source, source, map)
+                .into_iter()
+                .map(Action::Measure)
+                .collect(),
+        ))
+    }
+}
+
+/// scaling events, like `Scaled to web@4:Standard-1X by user ...`.
+#[derive(Debug)]
+pub(crate) struct Scaling;
+
+impl LineClassifier for Scaling {
+    fn name(&self) -> &'static str {
+        "scaling"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        let log = &line.log;
+        if !matches!(log.kind, Kind::App)
+            || !log.text.starts_with("Scaled to")
+            || !destination.has_metric_clients()
+        {
+            return Ok(None);
+        }
+
+        let Ok((_, (events, user))) = parse_scaling_event(log.text) else {
+            return Ok(Some(Vec::new()));
+        };
+
+        debug!("trying to report scaling metrics");
+
+        // store the scaling events in a cache so we can regularly re-send them.
+        let mut actions = Vec::new();
+        if destination.update_scaling_events(events.iter().map(Into::into).collect(), log.timestamp)
+        {
+            info!(
+                destination = destination.name,
+                ?events,
+                "re-sent dyno counts overwrote a scaling event"
+            );
+            actions.extend(
+                generate_librato_scaling_conflict_metric(&log.timestamp, log.source)
+                    .map(Action::Measure),
+            );
+        }
+        destination.record_scaling_time(log.timestamp);
+
+        actions.extend(
+            generate_librato_scaling_metrics(
+                &log.timestamp,
+                &events,
+                destination.settings.scaling_total,
+                destination.settings.dyno_count_grouping,
+                destination.settings.metric_name_case,
+                Some(user),
+            )
+            .into_iter()
+            .map(Action::Measure),
+        );
+        Ok(Some(actions))
+    }
+}
+
+/// JSON app log lines, see `sentry_json_logs`.
+#[derive(Debug)]
+pub(crate) struct JsonAppLog;
+
+impl LineClassifier for JsonAppLog {
+    fn name(&self) -> &'static str {
+        "json_app_log"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        Ok(
+            forwarded_json_app_log(&destination.settings, &line.log).map(|json_log| {
+                vec![Action::Report(generate_json_app_log_message(
+                    &json_log, &line.log,
+                ))]
+            }),
+        )
+    }
+}
+
+/// app log lines containing one of the `sentry_app_patterns`.
+#[derive(Debug)]
+pub(crate) struct AppPattern;
+
+impl LineClassifier for AppPattern {
+    fn name(&self) -> &'static str {
+        "app_pattern"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        Ok(
+            matching_app_pattern(&destination.settings, &line.log).map(|pattern| {
+                vec![Action::Report(generate_app_error_message(
+                    pattern, &line.log,
+                ))]
+            }),
+        )
+    }
+}
+
+/// everything else, sent to the unclassified sentry project when configured.
+#[derive(Debug)]
+pub(crate) struct Unclassified;
+
+impl LineClassifier for Unclassified {
+    fn name(&self) -> &'static str {
+        "unclassified"
+    }
+
+    fn classify<'a>(
+        &self,
+        destination: &Destination,
+        line: &ParsedLine<'a>,
+        _batch: &mut BatchState<'a>,
+    ) -> Result<Option<Vec<Action>>> {
+        if destination.unclassified_sentry_client.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(vec![Action::ReportUnclassified(
+            generate_unclassified_message(&destination.name, &line.log),
+        )]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::Client;
+    use std::sync::Arc;
+    use test_case::test_case;
+
+    fn destination(settings: &str) -> Destination {
+        Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            Some(crate::librato::Client::new(
+                "username",
+                "token",
+                None,
+                "invalid_endpoint",
+            )),
+        )
+        .with_settings(settings.parse().unwrap())
+    }
+
+    fn logline(kind: Kind, source: &'static str, text: &'static str) -> LogLine<'static> {
+        LogLine {
+            timestamp: "2022-12-05T08:59:21.850424+00:00".parse().unwrap(),
+            source,
+            kind,
+            text,
+        }
+    }
+
+    #[test_case(
+        "Starting process with command `bundle exec puma`",
+        Kind::Heroku,
+        Some(LifecycleEvent::Starting)
+    )]
+    #[test_case(
+        "Stopping all processes with SIGTERM",
+        Kind::Heroku,
+        Some(LifecycleEvent::Stopping)
+    )]
+    #[test_case(
+        "State changed from starting to up",
+        Kind::Heroku,
+        Some(LifecycleEvent::Up)
+    )]
+    #[test_case("State changed from up to down", Kind::Heroku, None)]
+    #[test_case("State changed from starting to up", Kind::App, None)]
+    fn test_lifecycle_event(text: &'static str, kind: Kind, expected: Option<LifecycleEvent>) {
+        assert_eq!(lifecycle_event(&logline(kind, "web.1", text)), expected);
+    }
+
+    #[test]
+    fn test_parsed_line_pairs() {
+        let line = ParsedLine::new(logline(Kind::Heroku, "router", "at=info status=200"));
+        assert_eq!(line.pairs().unwrap().get("status"), Some(&"200"));
+
+        let line = ParsedLine::new(logline(Kind::App, "web.1", "=="));
+        assert!(line.pairs().is_err());
+    }
+
+    #[test]
+    fn test_router_classifier() {
+        let line = ParsedLine::new(logline(
+            Kind::Heroku,
+            "router",
+            "at=error code=H12 desc=\"Request timeout\" method=GET path=/ \
+            host=myapp.herokuapp.com dyno=web.1 service=30000ms status=503 bytes=0",
+        ));
+
+        let actions = Router
+            .classify(&destination(""), &line, &mut BatchState::default())
+            .unwrap()
+            .unwrap();
+
+        // response bytes, router error & 2 timeout service metrics, sentry message
+        assert_eq!(actions.len(), 5);
+        assert!(matches!(actions[4], Action::Report(_)));
+    }
+
+    #[test]
+    fn test_router_classifier_ignores_app_lines() {
+        let line = ParsedLine::new(logline(Kind::App, "router", "at=info"));
+
+        assert!(Router
+            .classify(&destination(""), &line, &mut BatchState::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_lifecycle_classifier_boot_duration() {
+        let destination = destination("");
+        let mut batch = BatchState::default();
+
+        let start = ParsedLine::new(LogLine {
+            timestamp: "2022-12-05T08:59:16+00:00".parse().unwrap(),
+            ..logline(
+                Kind::Heroku,
+                "web.1",
+                "Starting process with command `bundle exec puma`",
+            )
+        });
+        let up = ParsedLine::new(LogLine {
+            timestamp: "2022-12-05T08:59:21+00:00".parse().unwrap(),
+            ..logline(Kind::Heroku, "web.1", "State changed from starting to up")
+        });
+
+        Lifecycle
+            .classify(&destination, &start, &mut batch)
+            .unwrap()
+            .unwrap();
+        let actions = Lifecycle
+            .classify(&destination, &up, &mut batch)
+            .unwrap()
+            .unwrap();
+
+        let [Action::Measure(measurement)] = &actions[..] else {
+            panic!("expected a single measurement, got {:?}", actions);
+        };
+        assert_eq!(measurement.name, "dyno.boot_duration_ms");
+        assert_eq!(measurement.value, 5000.0);
+    }
+
+    #[test_case("dyno_heartbeats=true", 1; "enabled")]
+    #[test_case("", 0; "disabled")]
+    fn test_heartbeat_classifier(settings: &str, expected: usize) {
+        let line = ParsedLine::new(logline(Kind::App, "dramatiqworker.1", ""));
+
+        let actions = Heartbeat
+            .classify(&destination(settings), &line, &mut BatchState::default())
+            .unwrap();
+
+        // empty lines are always handled here
+        assert_eq!(actions.unwrap().len(), expected);
+    }
+
+    #[test_case("addon_sources=heroku-postgres", 2; "configured")]
+    #[test_case("", 0; "not configured")]
+    fn test_addon_metrics_classifier(settings: &str, expected: usize) {
+        let line = ParsedLine::new(logline(
+            Kind::App,
+            "heroku-postgres",
+            "source=DATABASE addon=postgresql-curly-12345 \
+            sample#db_size=4709591bytes sample#tables=12",
+        ));
+
+        let actions = AddonMetrics
+            .classify(&destination(settings), &line, &mut BatchState::default())
+            .unwrap();
+
+        assert_eq!(actions.map_or(0, |actions| actions.len()), expected);
+    }
+
+    #[test]
+    fn test_default_classifier_names_are_unique() {
+        let mut names: Vec<_> = default_classifiers().iter().map(|c| c.name()).collect();
+        let count = names.len();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+
+    #[test]
+    fn test_classifier_names() {
+        let names: Vec<_> = default_classifiers().iter().map(|c| c.name()).collect();
+        assert_eq!(names, CLASSIFIER_NAMES);
+    }
+
+    #[test]
+    fn test_metric_classifiers_skip_non_logfmt_lines() {
+        let destination = destination("sample_metrics=true addon_sources=heroku-postgres");
+
+        let line = ParsedLine::new(logline(Kind::Heroku, "web.1", "== sample#load_avg_1m=0.01"));
+        assert!(SampleMetrics
+            .classify(&destination, &line, &mut BatchState::default())
+            .unwrap()
+            .is_none());
+
+        let line = ParsedLine::new(logline(
+            Kind::App,
+            "heroku-postgres",
+            "== sample#db_size=4709591bytes",
+        ));
+        assert!(AddonMetrics
+            .classify(&destination, &line, &mut BatchState::default())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// remembers recently received batches, so we can ignore
+/// deliveries that are retried by logplex.
+#[derive(Debug)]
+pub(crate) struct RecentBatches {
+    /// how long we remember a batch. A zero duration disables the deduplication.
+    window: Duration,
+    seen: Mutex<Seen>,
+}
+
+/// the hashes of the batches, and when we saw them in the order we saw them,
+/// so expiring them only looks at the oldest ones.
+#[derive(Debug, Default)]
+struct Seen {
+    hashes: HashSet<u64>,
+    by_time: VecDeque<(Instant, u64)>,
+}
+
+impl RecentBatches {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(Seen::default()),
+        }
+    }
+
+    /// remember the batch identified by `key`.
+    ///
+    /// Returns `true` when the same batch was already seen inside the window.
+    pub(crate) fn check_and_insert(&self, key: impl Hash) -> bool {
+        if self.window.is_zero() {
+            return false;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        while let Some(&(seen_at, expired)) = seen.by_time.front() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            seen.by_time.pop_front();
+            seen.hashes.remove(&expired);
+        }
+
+        if !seen.hashes.insert(hash) {
+            return true;
+        }
+
+        seen.by_time.push_back((now, hash));
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_is_detected() {
+        let recent = RecentBatches::new(Duration::from_secs(60));
+
+        assert!(!recent.check_and_insert(("token", "body")));
+        assert!(recent.check_and_insert(("token", "body")));
+        assert!(!recent.check_and_insert(("other_token", "body")));
+        assert!(!recent.check_and_insert(("token", "other body")));
+    }
+
+    #[test]
+    fn test_zero_window_disables_deduplication() {
+        let recent = RecentBatches::new(Duration::ZERO);
+
+        assert!(!recent.check_and_insert(("token", "body")));
+        assert!(!recent.check_and_insert(("token", "body")));
+    }
+
+    #[test]
+    fn test_batches_expire_after_window() {
+        let recent = RecentBatches::new(Duration::from_millis(10));
+
+        assert!(!recent.check_and_insert(("token", "body")));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!recent.check_and_insert(("token", "body")));
+        assert!(!recent.check_and_insert(("token", "other body")));
+
+        let seen = recent.seen.lock().unwrap();
+        assert_eq!(seen.hashes.len(), 2);
+        assert_eq!(seen.by_time.len(), 2);
+    }
+}
@@ -1,6 +1,9 @@
 use axum_extra::headers::{Error, Header, HeaderName, HeaderValue};
 
 pub static LOGPLEX_DRAIN_TOKEN: HeaderName = HeaderName::from_static("logplex-drain-token");
+pub static LOGPLEX_FRAME_ID: HeaderName = HeaderName::from_static("logplex-frame-id");
+pub static LOGPLEX_MSG_COUNT: HeaderName = HeaderName::from_static("logplex-msg-count");
+pub static X_ENVIRONMENT: HeaderName = HeaderName::from_static("x-environment");
 
 #[derive(Debug, Hash, PartialEq, Eq)]
 pub(crate) struct LogplexDrainToken(String);
@@ -37,6 +40,111 @@ impl Header for LogplexDrainToken {
     }
 }
 
+/// unique id of a logplex batch, for tracing.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LogplexFrameId(String);
+
+impl<'a> LogplexFrameId {
+    pub(crate) fn as_str(&'a self) -> &'a str {
+        &self.0
+    }
+}
+
+impl Header for LogplexFrameId {
+    fn name() -> &'static HeaderName {
+        &LOGPLEX_FRAME_ID
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values.next().ok_or_else(Error::invalid)?;
+        Ok(LogplexFrameId(
+            value.to_str().map_err(|_| Error::invalid())?.to_owned(),
+        ))
+    }
+
+    fn encode<E>(&self, values: &mut E)
+    where
+        E: Extend<HeaderValue>,
+    {
+        let value =
+            HeaderValue::from_str(&self.0).expect("invalid header value for logplex-frame-id");
+
+        values.extend(std::iter::once(value));
+    }
+}
+
+/// how many log lines logplex put into the batch.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LogplexMsgCount(pub(crate) usize);
+
+impl Header for LogplexMsgCount {
+    fn name() -> &'static HeaderName {
+        &LOGPLEX_MSG_COUNT
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values.next().ok_or_else(Error::invalid)?;
+        Ok(LogplexMsgCount(
+            value
+                .to_str()
+                .map_err(|_| Error::invalid())?
+                .parse()
+                .map_err(|_| Error::invalid())?,
+        ))
+    }
+
+    fn encode<E>(&self, values: &mut E)
+    where
+        E: Extend<HeaderValue>,
+    {
+        values.extend(std::iter::once(HeaderValue::from(self.0)));
+    }
+}
+
+/// sentry environment for all events of a batch, for forwarders
+/// sending the logs of multiple environments to one drain.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct XEnvironment(String);
+
+impl<'a> XEnvironment {
+    pub(crate) fn as_str(&'a self) -> &'a str {
+        &self.0
+    }
+}
+
+impl Header for XEnvironment {
+    fn name() -> &'static HeaderName {
+        &X_ENVIRONMENT
+    }
+
+    fn decode<'i, I>(values: &mut I) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        let value = values.next().ok_or_else(Error::invalid)?;
+        let value = value.to_str().map_err(|_| Error::invalid())?.trim();
+        if value.is_empty() {
+            return Err(Error::invalid());
+        }
+        Ok(XEnvironment(value.to_owned()))
+    }
+
+    fn encode<E>(&self, values: &mut E)
+    where
+        E: Extend<HeaderValue>,
+    {
+        let value = HeaderValue::from_str(&self.0).expect("invalid header value for x-environment");
+
+        values.extend(std::iter::once(value));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -60,4 +168,58 @@ mod tests {
             LogplexDrainToken("token".into())
         );
     }
+
+    #[test]
+    fn test_decode_logplex_frame_id() {
+        let mut map = HeaderMap::new();
+        map.append(
+            LogplexFrameId::name(),
+            "09C557EAFCFB6CF2740EE62F62971098".parse().unwrap(),
+        );
+        assert_eq!(
+            map.typed_get::<LogplexFrameId>().unwrap(),
+            LogplexFrameId("09C557EAFCFB6CF2740EE62F62971098".into())
+        );
+    }
+
+    #[test]
+    fn test_encode_logplex_msg_count() {
+        let mut map = HeaderMap::new();
+        map.typed_insert(LogplexMsgCount(3));
+        assert_eq!(map["logplex-msg-count"], "3");
+    }
+
+    #[test]
+    fn test_decode_logplex_msg_count() {
+        let mut map = HeaderMap::new();
+        map.append(LogplexMsgCount::name(), "3".parse().unwrap());
+        assert_eq!(
+            map.typed_get::<LogplexMsgCount>().unwrap(),
+            LogplexMsgCount(3)
+        );
+    }
+
+    #[test]
+    fn test_decode_invalid_logplex_msg_count() {
+        let mut map = HeaderMap::new();
+        map.append(LogplexMsgCount::name(), "many".parse().unwrap());
+        assert!(map.typed_get::<LogplexMsgCount>().is_none());
+    }
+
+    #[test]
+    fn test_decode_x_environment() {
+        let mut map = HeaderMap::new();
+        map.append(XEnvironment::name(), "staging".parse().unwrap());
+        assert_eq!(
+            map.typed_get::<XEnvironment>().unwrap(),
+            XEnvironment("staging".into())
+        );
+    }
+
+    #[test]
+    fn test_decode_empty_x_environment() {
+        let mut map = HeaderMap::new();
+        map.append(XEnvironment::name(), " ".parse().unwrap());
+        assert!(map.typed_get::<XEnvironment>().is_none());
+    }
 }
@@ -0,0 +1,45 @@
+use axum_extra::headers::HeaderName;
+use std::env;
+use uuid::Uuid;
+
+/// so the metric providers can identify our traffic in support requests.
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// sent with every outbound request and logged on errors, so a failed
+/// request can be found on the provider's side.
+pub static REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+pub(crate) fn new_request_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// our `USER_AGENT`, with the heroku app name when it's set,
+/// so the providers can tell our apps apart.
+pub(crate) fn user_agent() -> String {
+    user_agent_for_app(env::var("HEROKU_APP_NAME").ok().as_deref())
+}
+
+fn user_agent_for_app(app_name: Option<&str>) -> String {
+    match app_name.filter(|name| !name.is_empty()) {
+        Some(app_name) => format!("{} ({})", USER_AGENT, app_name),
+        None => USER_AGENT.to_owned(),
+    }
+}
+
+/// a [`reqwest::ClientBuilder`] with the settings all outbound clients share.
+pub(crate) fn builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder().user_agent(user_agent())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(None, USER_AGENT.to_owned(); "without app name")]
+    #[test_case(Some(""), USER_AGENT.to_owned(); "empty app name")]
+    #[test_case(Some("myapp"), format!("{} (myapp)", USER_AGENT); "with app name")]
+    fn test_user_agent_for_app(app_name: Option<&str>, expected: String) {
+        assert_eq!(user_agent_for_app(app_name), expected);
+    }
+}
@@ -0,0 +1,214 @@
+//! Reports errors and metrics from heroku log drains to sentry and librato.
+//!
+//! The binary runs [`run`] with the configuration from the environment.
+//! Other services can build a [`Config`] with [`Config::builder`], add their
+//! own [`LineClassifier`]s, and serve the drain endpoint with [`build_app`].
+
+use anyhow::{bail, Result};
+use sentry::integrations::{
+    panic as sentry_panic, tower as sentry_tower, tracing as sentry_tracing,
+};
+use std::{borrow::Cow, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpListener, signal};
+use tower::ServiceBuilder;
+use tower_http::trace::TraceLayer;
+use tracing::{info, instrument};
+use tracing_subscriber::{prelude::*, reload, EnvFilter};
+
+mod access_log;
+mod background;
+mod circuit_breaker;
+mod classifiers;
+mod config;
+mod dedup;
+mod extractors;
+mod forward;
+mod http_client;
+mod librato;
+mod log_parser;
+mod metrics;
+mod reporter;
+mod routes;
+mod sampling;
+mod sentry_transport;
+mod server;
+mod stats;
+#[cfg(test)]
+mod test_utils;
+mod throttle;
+
+pub use classifiers::{Action, BatchState, LineClassifier, ParsedLine};
+pub use config::{Config, ConfigBuilder, Destination, DestinationSettings};
+pub use librato::{Client as LibratoClient, Measurement};
+pub use log_parser::{Kind, LogLine};
+pub use reporter::SentryMessage;
+pub use server::build_app;
+
+/// run the reporter with the configuration from the environment,
+/// until it receives a shutdown signal.
+pub async fn run() -> Result<()> {
+    let mut config = config::Config::init_from_env()?;
+
+    let heroku_release = std::env::var("HEROKU_RELEASE_VERSION").ok();
+
+    let mut tracing_subscriber_layer = tracing_subscriber::fmt::layer();
+
+    if heroku_release.is_some() {
+        // we don't want ansi colors on heroku since logentries doesnt understand them.
+        tracing_subscriber_layer = tracing_subscriber_layer.with_ansi(false);
+    }
+
+    // reloadable, so we can change the log level during incidents.
+    let (env_filter, env_filter_handle) = reload::Layer::new(EnvFilter::from_default_env());
+    config.log_filter_reloader = Some(config::LogFilterReloader::new(move |directives| {
+        let env_filter = EnvFilter::try_new(directives)?;
+        env_filter_handle.reload(env_filter)?;
+        Ok(())
+    }));
+
+    let tracing_registry = tracing_subscriber::registry()
+        .with(tracing_subscriber_layer)
+        .with(env_filter);
+
+    let _sentry_guard = if let Some(sentry_dsn) = &config.sentry_dsn {
+        tracing_registry.with(sentry_tracing::layer()).init();
+        Some(sentry::init((
+            sentry_dsn.clone(),
+            sentry::ClientOptions {
+                release: heroku_release.map(Cow::Owned),
+                attach_stacktrace: true,
+                debug: config.sentry_debug,
+                traces_sample_rate: config.sentry_traces_sample_rate,
+                ..Default::default()
+            }
+            .add_integration(sentry_panic::PanicIntegration::default()),
+        )))
+    } else {
+        tracing_registry.init();
+        None
+    };
+
+    let config = Arc::new(config);
+    info!(?config, "config loaded");
+
+    // logged here, init_from_env runs before tracing is set up.
+    let summary = config.mappings_summary();
+    info!(
+        loaded = summary.loaded,
+        invalid = summary.invalid,
+        disabled = summary.disabled,
+        with_librato = ?summary.with_librato,
+        "sentry mappings summary"
+    );
+
+    // the background tasks get the current config from the handle,
+    // so they use the new one after a `POST /reload`.
+    let handle = Arc::new(config::ConfigHandle::new(config.clone()));
+
+    info!("starting background task: resend scaling events");
+    tokio::spawn(background::resend_scaling_events(handle.clone()));
+
+    if let Some(ref url) = config.heartbeat_url {
+        info!(
+            interval = ?config.heartbeat_interval,
+            "starting background task: heartbeat pings"
+        );
+        tokio::spawn(background::ping_heartbeat_url(
+            url.clone(),
+            config.heartbeat_interval,
+        ));
+    }
+
+    if let Some(interval) = config.access_log.interval() {
+        info!(
+            ?interval,
+            "starting background task: flush access log rollups"
+        );
+        tokio::spawn(background::flush_access_log(handle.clone(), interval));
+    }
+
+    // started without forwarding or spillover too, a reload might add them.
+    info!(
+        interval = ?forward::FLUSH_INTERVAL,
+        "starting background task: flush forward queues"
+    );
+    tokio::spawn(background::flush_forward_queues(handle.clone()));
+
+    info!(
+        interval = ?background::SPILLOVER_REPLAY_INTERVAL,
+        "starting background task: replay spilled measurements"
+    );
+    tokio::spawn(background::replay_spilled_measurements(handle.clone()));
+
+    let port = config.port;
+    let app = server::build_reloadable_app(handle.clone()).layer(
+        ServiceBuilder::new()
+            .layer(TraceLayer::new_for_http())
+            .layer(sentry_tower::NewSentryLayer::new_from_top())
+            .layer(sentry_tower::SentryHttpLayer::with_transaction()),
+    );
+
+    let addr = SocketAddr::new(config.bind_addr, port);
+    info!(?addr, "starting server");
+
+    let listener = TcpListener::bind(addr).await?;
+    axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    handle.current().shutdown().await;
+
+    Ok(())
+}
+
+/// print a summary of all configured sentry mappings,
+/// and fail when one of them is invalid.
+pub fn validate_config() -> Result<()> {
+    let results = config::Config::validate_env()?;
+    if results.is_empty() {
+        bail!("no sentry mappings found");
+    }
+
+    let mut failed = 0;
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("ok    {}", name),
+            Err(err) => {
+                failed += 1;
+                println!("error {}: {:#}", name, err);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} of {} sentry mappings are invalid",
+            failed,
+            results.len()
+        );
+    }
+    Ok(())
+}
+
+#[instrument]
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("signal received, starting graceful shutdown");
+}
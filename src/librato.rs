@@ -1,27 +1,54 @@
+use crate::{
+    circuit_breaker::CircuitBreaker,
+    http_client::{self, REQUEST_ID},
+};
 use anyhow::{bail, Result};
 use chrono::{DateTime, FixedOffset};
 use crossbeam_utils::sync::WaitGroup;
 use serde_json::json;
 use std::{
-    sync::Mutex,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
-use tracing::{debug, error};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, warn};
 
 const MAX_MEASURE_MEASUREMENTS_PER_REQUEST: usize = 300; // max as per documentation
+const MAX_SOURCE_LENGTH: usize = 255; // max as per documentation
+const MAX_NAME_LENGTH: usize = 255; // max as per documentation
+/// hard limit for the queue, so a longer backend outage can't lead
+/// to an OOM. The oldest measurements are dropped first.
+const MAX_QUEUE_LEN: usize = 10_000;
 const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+#[cfg(not(test))]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(test)]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+const POOL_MAX_IDLE_PER_HOST: usize = 4;
+/// max concurrent background sends per client, so a slow librato
+/// doesn't lead to an unbounded amount of pending requests. Flushes
+/// with all sends pending keep the queue instead.
+const MAX_CONCURRENT_SENDS: usize = 4;
+/// after this many failed sends in a row we stop sending for
+/// `CIRCUIT_BREAKER_COOLDOWN`, and drop measurements instead.
+const CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(5 * 60);
 #[cfg(not(test))]
 const DEFAULT_METRIC_ENDPOINT: &str = "https://metrics-api.librato.com/v1/metrics";
 
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) enum Kind {
-    #[allow(dead_code)]
     Counter,
     Gauge,
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub(crate) struct Measurement {
+pub struct Measurement {
     pub(crate) kind: Kind,
     pub(crate) measure_time: DateTime<FixedOffset>,
     pub(crate) value: f64,
@@ -29,9 +56,136 @@ pub(crate) struct Measurement {
     pub(crate) source: String,
 }
 
+impl Measurement {
+    /// a measurement, rejecting empty names and values librato
+    /// can't store, like `NaN`.
+    pub(crate) fn new(
+        kind: Kind,
+        measure_time: DateTime<FixedOffset>,
+        name: impl Into<String>,
+        source: impl Into<String>,
+        value: f64,
+    ) -> Result<Self> {
+        let name = name.into();
+        if name.is_empty() {
+            bail!("empty measurement name");
+        }
+        if !value.is_finite() {
+            bail!("invalid value for {}: {}", name, value);
+        }
+        Ok(Self {
+            kind,
+            measure_time,
+            value,
+            name,
+            source: source.into(),
+        })
+    }
+
+    pub(crate) fn gauge(
+        measure_time: DateTime<FixedOffset>,
+        name: impl Into<String>,
+        source: impl Into<String>,
+        value: f64,
+    ) -> Result<Self> {
+        Self::new(Kind::Gauge, measure_time, name, source, value)
+    }
+}
+
+/// graphite plaintext line with the source as tag, like
+/// `dyno_count;source=web 4 1670230761`. The alternate form (`{:#}`)
+/// has a float timestamp with milliseconds, like `1670230761.123`.
+impl std::fmt::Display for Measurement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{};source={} {} ", self.name, self.source, self.value)?;
+        if f.alternate() {
+            write!(
+                f,
+                "{}.{:03}",
+                self.measure_time.timestamp(),
+                self.measure_time.timestamp_subsec_millis()
+            )
+        } else {
+            write!(f, "{}", self.measure_time.timestamp())
+        }
+    }
+}
+
+/// the precision of the `measure_time` we send.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TimeResolution {
+    /// whole seconds, what the librato API documents.
+    #[default]
+    Seconds,
+    /// seconds with milliseconds as fraction, for backends accepting them,
+    /// so bursts of measurements keep their order.
+    Millis,
+}
+
+impl FromStr for TimeResolution {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "seconds" => Ok(TimeResolution::Seconds),
+            "millis" => Ok(TimeResolution::Millis),
+            _ => bail!("unknown time resolution: {}", input),
+        }
+    }
+}
+
+impl TimeResolution {
+    /// the graphite line of the measurement, see the `Display` of
+    /// [`Measurement`], for the stdout metric sink.
+    pub(crate) fn line(&self, measurement: &Measurement) -> String {
+        match self {
+            TimeResolution::Seconds => format!("{}", measurement),
+            TimeResolution::Millis => format!("{:#}", measurement),
+        }
+    }
+
+    /// the unix timestamp for the request body.
+    fn timestamp(&self, time: &DateTime<FixedOffset>) -> serde_json::Value {
+        match self {
+            TimeResolution::Seconds => time.timestamp().into(),
+            TimeResolution::Millis => (time.timestamp_millis() as f64 / 1000.0).into(),
+        }
+    }
+}
+
+/// make a string usable as librato source.
+/// Librato only allows `[-:A-Za-z0-9_.]` in sources, all other characters
+/// are replaced with `_`.
+pub(crate) fn sanitize_source(source: &str) -> String {
+    sanitize(source, MAX_SOURCE_LENGTH)
+}
+
+/// make a string usable as librato metric name, for names containing
+/// values from log lines. Same allowed characters as in sources,
+/// librato silently drops metrics with other names.
+pub(crate) fn sanitize_name(name: &str) -> String {
+    sanitize(name, MAX_NAME_LENGTH)
+}
+
+fn sanitize(input: &str, max_length: usize) -> String {
+    input
+        .chars()
+        .take(max_length)
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || matches!(ch, '-' | ':' | '_' | '.') {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 struct State {
     queue: Vec<Measurement>,
+    /// when the first measurement was added to the currently empty queue.
+    oldest_queued: Option<Instant>,
     last_flush: Instant,
     waitgroup: Option<WaitGroup>,
 }
@@ -39,19 +193,51 @@ struct State {
 impl State {
     fn reset(&mut self) {
         self.queue.clear();
+        self.oldest_queued = None;
         self.last_flush = Instant::now();
     }
 }
 
+/// the chunks of a send that librato didn't accept.
+#[derive(Debug)]
+struct SendError {
+    /// measurements in the failed chunks.
+    unsent: usize,
+    errors: Vec<anyhow::Error>,
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} measurements not sent: {:?}",
+            self.unsent, self.errors
+        )
+    }
+}
+
+impl std::error::Error for SendError {}
+
 /// Librato client to send measurements to librato.
 /// collects metrics in an internal queue and regularly send them to librato
 /// in the background.
 #[derive(Debug)]
-pub(crate) struct Client {
+pub struct Client {
     pub(crate) username: String,
     token: String,
     #[cfg(test)]
     endpoint: String,
+    http_client: reqwest::Client,
+    /// only keep the latest queued gauge per name & source.
+    coalesce_gauges: bool,
+    /// shadow clients only log send errors as warnings.
+    shadow: bool,
+    time_resolution: TimeResolution,
+    max_queue_len: usize,
+    send_permits: Arc<Semaphore>,
+    circuit_breaker: Arc<CircuitBreaker>,
+    /// measurements dropped because the queue was full or their flush failed.
+    dropped_measurements: Arc<AtomicU64>,
     state: Mutex<State>,
 }
 
@@ -67,37 +253,166 @@ impl Client {
             token: token.into(),
             #[cfg(test)]
             endpoint: endpoint.into(),
+            http_client: http_client::builder()
+                .gzip(true)
+                .pool_max_idle_per_host(POOL_MAX_IDLE_PER_HOST)
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("could not build HTTP client"),
+            coalesce_gauges: false,
+            shadow: false,
+            time_resolution: TimeResolution::default(),
+            max_queue_len: MAX_QUEUE_LEN,
+            send_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_SENDS)),
+            circuit_breaker: Arc::new(CircuitBreaker::new(
+                CIRCUIT_BREAKER_FAILURE_THRESHOLD,
+                CIRCUIT_BREAKER_COOLDOWN,
+            )),
+            dropped_measurements: Arc::new(AtomicU64::new(0)),
             state: Mutex::new(State {
                 waitgroup,
                 queue: Vec::new(),
+                oldest_queued: None,
                 last_flush: Instant::now(),
             }),
         }
     }
 
+    /// when enabled, a newly added gauge replaces queued gauges with
+    /// the same name and source, so we only send the latest value.
+    /// Counters are never coalesced.
+    pub(crate) fn with_gauge_coalescing(mut self, coalesce_gauges: bool) -> Self {
+        self.coalesce_gauges = coalesce_gauges;
+        self
+    }
+
+    /// shadow clients are used to test a new account or backend, so their
+    /// send errors are only logged as warnings, which don't end up in sentry.
+    pub(crate) fn with_shadow(mut self, shadow: bool) -> Self {
+        self.shadow = shadow;
+        self
+    }
+
+    pub(crate) fn with_time_resolution(mut self, time_resolution: TimeResolution) -> Self {
+        self.time_resolution = time_resolution;
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn queue_len(&self) -> usize {
+        self.state.lock().unwrap().queue.len()
+    }
+
+    /// `true` while sends are skipped after repeated failures,
+    /// for example when librato rate limits us.
+    pub(crate) fn is_backed_off(&self) -> bool {
+        self.circuit_breaker.is_open()
+    }
+
+    /// `true` when the last send succeeded, or nothing failed yet.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.circuit_breaker.is_closed()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_backed_off(&self, backed_off: bool) {
+        if backed_off {
+            for _ in 0..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+                self.circuit_breaker.record_failure();
+            }
+        } else {
+            self.circuit_breaker.record_success();
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn queued_measurements(&self) -> Vec<Measurement> {
+        self.state.lock().unwrap().queue.clone()
+    }
+
+    /// how many measurements were dropped because the queue was full,
+    /// or because sending them failed.
+    pub(crate) fn dropped_measurements(&self) -> u64 {
+        self.dropped_measurements.load(Ordering::Relaxed)
+    }
+
+    /// how long the oldest measurement has been waiting in the queue.
+    /// If this keeps growing, flushing is stuck.
+    pub(crate) fn oldest_measurement_age(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .unwrap()
+            .oldest_queued
+            .map(|queued| queued.elapsed())
+    }
+
     /// add measurement to the local queue of measurements to be sent.
     /// Will regularly flush the queue and send the measurements to librato
     /// in the background.
-    pub(crate) fn add_measurement(&self, measurement: Measurement) {
+    ///
+    /// Returns `false` when the measurement was dropped, because
+    /// the last sends failed and the circuit breaker is open.
+    pub(crate) fn add_measurement(&self, measurement: Measurement) -> bool {
+        if self.circuit_breaker.is_open() {
+            return false;
+        }
+
         let mut state = self.state.lock().unwrap();
+        if self.coalesce_gauges && matches!(measurement.kind, Kind::Gauge) {
+            state.queue.retain(|queued| {
+                !(matches!(queued.kind, Kind::Gauge)
+                    && queued.name == measurement.name
+                    && queued.source == measurement.source)
+            });
+        }
         state.queue.push(measurement);
+        state.oldest_queued.get_or_insert_with(Instant::now);
+
+        if state.queue.len() > self.max_queue_len {
+            let overflow = state.queue.len() - self.max_queue_len;
+            state.queue.drain(..overflow);
+            self.dropped_measurements
+                .fetch_add(overflow as u64, Ordering::Relaxed);
+            debug!(
+                overflow,
+                username = self.username,
+                "queue full, dropped the oldest measurements"
+            );
+        }
 
         if state.queue.len() <= MAX_MEASURE_MEASUREMENTS_PER_REQUEST
             && state.last_flush.elapsed() <= FLUSH_INTERVAL
         {
-            return;
+            return true;
         }
 
+        // with all sends pending we keep the queue for the next flush,
+        // it's still limited by `max_queue_len`.
+        let Ok(permit) = self.send_permits.clone().try_acquire_owned() else {
+            debug!(
+                username = self.username,
+                "all sends pending, keeping the queue"
+            );
+            return true;
+        };
+
         debug!(?state.queue, "triggering background flushing to librato");
         tokio::spawn({
+            let http_client = self.http_client.clone();
             let queue = state.queue.clone();
             let username = self.username.clone();
             let token = self.token.clone();
             #[cfg(test)]
             let endpoint = self.endpoint.clone();
             let waitgroup = state.waitgroup.clone();
+            let shadow = self.shadow;
+            let time_resolution = self.time_resolution;
+            let circuit_breaker = self.circuit_breaker.clone();
+            let dropped_measurements = self.dropped_measurements.clone();
             async move {
                 if let Err(err) = Client::send(
+                    &http_client,
                     &username,
                     &token,
                     #[cfg(test)]
@@ -105,15 +420,28 @@ impl Client {
                     #[cfg(not(test))]
                     DEFAULT_METRIC_ENDPOINT,
                     &queue,
+                    time_resolution,
                 )
                 .await
                 {
-                    error!(?err, username, ?queue, "error sending metrics to librato");
+                    // the measurements of failed chunks are not re-queued,
+                    // since a rejected payload would fail again on every flush.
+                    circuit_breaker.record_failure();
+                    dropped_measurements.fetch_add(err.unsent as u64, Ordering::Relaxed);
+                    if shadow {
+                        warn!(?err, username, "error sending metrics to shadow librato");
+                    } else {
+                        error!(?err, username, ?queue, "error sending metrics to librato");
+                    }
+                } else {
+                    circuit_breaker.record_success();
                 }
+                drop(permit);
                 drop(waitgroup);
             }
         });
         state.reset();
+        true
     }
 
     /// shut down the librato client, sending all pending events to librato.
@@ -127,7 +455,16 @@ impl Client {
             queue
         };
         if !queue.is_empty() {
+            if self.circuit_breaker.is_open() {
+                self.dropped_measurements
+                    .fetch_add(queue.len() as u64, Ordering::Relaxed);
+                bail!(
+                    "circuit breaker is open, dropping {} measurements",
+                    queue.len()
+                );
+            }
             Client::send(
+                &self.http_client,
                 &self.username,
                 &self.token,
                 #[cfg(test)]
@@ -135,51 +472,109 @@ impl Client {
                 #[cfg(not(test))]
                 DEFAULT_METRIC_ENDPOINT,
                 &queue,
+                self.time_resolution,
             )
-            .await?;
+            .await
+            .inspect_err(|err| {
+                self.dropped_measurements
+                    .fetch_add(err.unsent as u64, Ordering::Relaxed);
+            })?;
         }
         Ok(())
     }
 
-    /// Actually send the measurements to librato using their API.
-    /// uses old source-based API, since that's what the Heroku addon instances use.
-    /// See http://api-docs-archive.librato.com/#create-a-metric
-    #[tracing::instrument(skip(token, measurements))]
+    /// send the measurements to librato, in chunks of the maximum request
+    /// size, since librato rejects larger requests.
+    /// The queue can get larger than that, for example when a flush failed.
+    /// A failed chunk doesn't stop the others, the error has the
+    /// measurements of all failed chunks.
     async fn send(
+        http_client: &reqwest::Client,
         username: impl AsRef<str> + std::fmt::Debug,
         token: impl AsRef<str> + std::fmt::Debug,
         endpoint: impl AsRef<str> + std::fmt::Debug,
         measurements: &[Measurement],
+        time_resolution: TimeResolution,
+    ) -> std::result::Result<(), SendError> {
+        let mut unsent = 0;
+        let mut errors = Vec::new();
+        for chunk in measurements.chunks(MAX_MEASURE_MEASUREMENTS_PER_REQUEST) {
+            if let Err(err) = Client::send_chunk(
+                http_client,
+                username.as_ref(),
+                token.as_ref(),
+                endpoint.as_ref(),
+                chunk,
+                time_resolution,
+            )
+            .await
+            {
+                unsent += chunk.len();
+                errors.push(err);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(SendError { unsent, errors })
+        }
+    }
+
+    /// the request body for the measurements, sorted by name, time
+    /// and source, so the output doesn't depend on how they were queued.
+    fn payload(measurements: &[Measurement], time_resolution: TimeResolution) -> serde_json::Value {
+        let mut measurements: Vec<_> = measurements.iter().collect();
+        measurements.sort_by(|a, b| {
+            (&a.name, a.measure_time, &a.source).cmp(&(&b.name, b.measure_time, &b.source))
+        });
+
+        json!({
+           "gauges": measurements.iter().filter(|m| matches!(m.kind, Kind::Gauge)).map(|m| {
+                json!({
+                    "measure_time": time_resolution.timestamp(&m.measure_time),
+                    "name": m.name,
+                    "value": m.value,
+                    "source": m.source,
+                })
+            }).collect::<Vec<_>>(),
+           "counters": measurements.iter().filter(|m| matches!(m.kind, Kind::Counter)).map(|m| {
+                json!({
+                    "measure_time": time_resolution.timestamp(&m.measure_time),
+                    "name": m.name,
+                    "value": m.value,
+                    "source": m.source,
+                })
+            }).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Actually send the measurements to librato using their API.
+    /// uses old source-based API, since that's what the Heroku addon instances use.
+    /// See http://api-docs-archive.librato.com/#create-a-metric
+    #[tracing::instrument(skip(http_client, token, measurements))]
+    async fn send_chunk(
+        http_client: &reqwest::Client,
+        username: &str,
+        token: &str,
+        endpoint: &str,
+        measurements: &[Measurement],
+        time_resolution: TimeResolution,
     ) -> Result<()> {
-        debug!("making API call to librato");
-        let response = reqwest::Client::new()
-            .post(endpoint.as_ref())
-            .basic_auth(username.as_ref(), Some(token.as_ref()))
-            .json(&json!({
-               "gauges": measurements.iter().filter(|m| matches!(m.kind, Kind::Gauge)).map(|m| {
-                    json!({
-                        "measure_time": m.measure_time.timestamp(),
-                        "name": m.name,
-                        "value": m.value,
-                        "source": m.source,
-                    })
-                }).collect::<Vec<_>>(),
-               "counters": measurements.iter().filter(|m| matches!(m.kind, Kind::Counter)).map(|m| {
-                    json!({
-                        "measure_time": m.measure_time.timestamp(),
-                        "name": m.name,
-                        "value": m.value,
-                        "source": m.source,
-                    })
-                }).collect::<Vec<_>>(),
-            }))
+        let request_id = http_client::new_request_id();
+        debug!(request_id, "making API call to librato");
+        let response = http_client
+            .post(endpoint)
+            .basic_auth(username, Some(token))
+            .header(&REQUEST_ID, &request_id)
+            .json(&Client::payload(measurements, time_resolution))
             .send()
             .await?;
 
         if !response.status().is_success() {
             bail!(
-                "librato returned an error code {}: {}",
+                "librato returned an error code {} for request {}: {}",
                 response.status(),
+                request_id,
                 response.text().await?
             );
         }
@@ -191,6 +586,241 @@ impl Client {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use test_case::test_case;
+
+    #[test_case("web.1", "web.1")]
+    #[test_case("/api/{number}/", "_api__number__")]
+    #[test_case("some source:with-dashes", "some_source:with-dashes")]
+    fn test_sanitize_source(input: &str, expected: &str) {
+        assert_eq!(sanitize_source(input), expected);
+    }
+
+    #[test_case("dyno_count.standard-1x", "dyno_count.standard-1x"; "valid")]
+    #[test_case("dyno_count.\"standard 1x\"", "dyno_count._standard_1x_"; "quotes and spaces")]
+    fn test_sanitize_name(input: &str, expected: &str) {
+        assert_eq!(sanitize_name(input), expected);
+    }
+
+    #[test]
+    fn test_sanitize_source_truncates() {
+        assert_eq!(sanitize_source(&"a".repeat(300)).len(), MAX_SOURCE_LENGTH);
+    }
+
+    #[test]
+    fn test_new_measurement() {
+        let ts = "2022-12-05T08:59:21+00:00".parse().unwrap();
+
+        assert_eq!(
+            Measurement::gauge(ts, "dyno_count", "web", 4.0).unwrap(),
+            Measurement {
+                kind: Kind::Gauge,
+                measure_time: ts,
+                value: 4.0,
+                name: "dyno_count".into(),
+                source: "web".into(),
+            }
+        );
+    }
+
+    #[test_case("", 1.0; "empty name")]
+    #[test_case("dyno_count", f64::NAN; "nan")]
+    #[test_case("dyno_count", f64::INFINITY; "infinite")]
+    #[test_case("dyno_count", f64::NEG_INFINITY; "negative infinite")]
+    fn test_new_measurement_invalid(name: &str, value: f64) {
+        let ts = "2022-12-05T08:59:21+00:00".parse().unwrap();
+
+        assert!(Measurement::gauge(ts, name, "web", value).is_err());
+    }
+
+    #[test]
+    fn test_measurement_display() {
+        let measurement = Measurement {
+            kind: Kind::Gauge,
+            measure_time: "2022-12-05T08:59:21+00:00".parse().unwrap(),
+            value: 4.0,
+            name: "dyno_count".into(),
+            source: "web".into(),
+        };
+
+        assert_eq!(
+            measurement.to_string(),
+            "dyno_count;source=web 4 1670230761"
+        );
+    }
+
+    #[test]
+    fn test_measurement_display_millis() {
+        let measurement = Measurement {
+            kind: Kind::Gauge,
+            measure_time: "2022-12-05T08:59:21.05+00:00".parse().unwrap(),
+            value: 4.0,
+            name: "dyno_count".into(),
+            source: "web".into(),
+        };
+
+        assert_eq!(
+            measurement.to_string(),
+            "dyno_count;source=web 4 1670230761"
+        );
+        assert_eq!(
+            format!("{measurement:#}"),
+            "dyno_count;source=web 4 1670230761.050"
+        );
+        assert_eq!(
+            TimeResolution::Seconds.line(&measurement),
+            "dyno_count;source=web 4 1670230761"
+        );
+        assert_eq!(
+            TimeResolution::Millis.line(&measurement),
+            "dyno_count;source=web 4 1670230761.050"
+        );
+    }
+
+    #[test_case(TimeResolution::Seconds, json!(1670230761); "seconds")]
+    #[test_case(TimeResolution::Millis, json!(1670230761.123); "millis")]
+    fn test_payload_time_resolution(time_resolution: TimeResolution, expected: serde_json::Value) {
+        let measurements = [Measurement {
+            kind: Kind::Gauge,
+            measure_time: "2022-12-05T08:59:21.123456+00:00".parse().unwrap(),
+            value: 4.0,
+            name: "dyno_count".into(),
+            source: "web".into(),
+        }];
+
+        let payload = Client::payload(&measurements, time_resolution);
+        assert_eq!(payload["gauges"][0]["measure_time"], expected);
+    }
+
+    #[test_case("seconds", Some(TimeResolution::Seconds))]
+    #[test_case("millis", Some(TimeResolution::Millis))]
+    #[test_case("ms", None)]
+    fn test_parse_time_resolution(input: &str, expected: Option<TimeResolution>) {
+        assert_eq!(input.parse().ok(), expected);
+    }
+
+    #[test]
+    fn test_payload_is_sorted() {
+        let measurement = |name: &str, time: &str, source: &str| Measurement {
+            kind: Kind::Gauge,
+            measure_time: time.parse().unwrap(),
+            value: 1.0,
+            name: name.into(),
+            source: source.into(),
+        };
+        let mut measurements = vec![
+            measurement("dyno_count", "2022-12-05T08:59:22+00:00", "web"),
+            measurement("alive", "2022-12-05T08:59:21+00:00", "web"),
+            measurement("dyno_count", "2022-12-05T08:59:21+00:00", "worker"),
+            measurement("dyno_count", "2022-12-05T08:59:21+00:00", "web"),
+        ];
+
+        let payload = Client::payload(&measurements, TimeResolution::Seconds);
+        measurements.reverse();
+        assert_eq!(
+            Client::payload(&measurements, TimeResolution::Seconds),
+            payload
+        );
+
+        let order: Vec<_> = payload["gauges"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|gauge| {
+                format!(
+                    "{} {} {}",
+                    gauge["name"].as_str().unwrap(),
+                    gauge["measure_time"],
+                    gauge["source"].as_str().unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                "alive 1670230761 web",
+                "dyno_count 1670230761 web",
+                "dyno_count 1670230761 worker",
+                "dyno_count 1670230762 web",
+            ]
+        );
+    }
+
+    #[test_case(false, 5; "disabled")]
+    #[test_case(true, 4; "enabled")]
+    fn test_gauge_coalescing(coalesce_gauges: bool, expected_len: usize) {
+        let client = Client::new("username", "token", None, "invalid_endpoint")
+            .with_gauge_coalescing(coalesce_gauges);
+
+        let measurement = |kind: Kind, value: f64, source: &str| Measurement {
+            kind,
+            measure_time: chrono::Utc::now().into(),
+            value,
+            name: "test".into(),
+            source: source.into(),
+        };
+
+        client.add_measurement(measurement(Kind::Gauge, 1.0, "web.1"));
+        client.add_measurement(measurement(Kind::Gauge, 2.0, "web.1"));
+        client.add_measurement(measurement(Kind::Gauge, 3.0, "web.2"));
+        client.add_measurement(measurement(Kind::Counter, 4.0, "web.1"));
+        client.add_measurement(measurement(Kind::Counter, 5.0, "web.1"));
+
+        let state = client.state.lock().unwrap();
+        assert_eq!(state.queue.len(), expected_len);
+        if coalesce_gauges {
+            assert_eq!(
+                state.queue.iter().map(|m| m.value).collect::<Vec<_>>(),
+                vec![2.0, 3.0, 4.0, 5.0]
+            );
+        }
+    }
+
+    #[test]
+    fn test_full_queue_drops_oldest() {
+        let mut client = Client::new("username", "token", None, "invalid_endpoint");
+        client.max_queue_len = 3;
+
+        for value in 1..=5 {
+            assert!(client.add_measurement(Measurement {
+                kind: Kind::Gauge,
+                measure_time: chrono::Utc::now().into(),
+                value: value as f64,
+                name: "test".into(),
+                source: "test".into(),
+            }));
+        }
+
+        assert_eq!(
+            client
+                .queued_measurements()
+                .iter()
+                .map(|m| m.value)
+                .collect::<Vec<_>>(),
+            vec![3.0, 4.0, 5.0]
+        );
+        assert_eq!(client.dropped_measurements(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_measurement_age() {
+        let client = Client::new("username", "token", None, "invalid_endpoint");
+        assert_eq!(client.oldest_measurement_age(), None);
+
+        client.add_measurement(Measurement {
+            kind: Kind::Gauge,
+            measure_time: chrono::Utc::now().into(),
+            value: 1.0,
+            name: "test".into(),
+            source: "test".into(),
+        });
+        client.state.lock().unwrap().oldest_queued = Some(Instant::now() - Duration::from_secs(30));
+
+        assert!(client.oldest_measurement_age().unwrap() >= Duration::from_secs(30));
+
+        // the failing shutdown still empties the queue
+        let _ = client.shutdown().await;
+        assert_eq!(client.oldest_measurement_age(), None);
+    }
 
     #[tokio::test]
     async fn test_empty_shutdown() {
@@ -211,6 +841,202 @@ mod tests {
         });
 
         assert!(client.shutdown().await.is_err());
+        assert_eq!(client.dropped_measurements(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_times_out_on_hanging_endpoint() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // accept connections, but never answer.
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let client = Client::new("username", "token", None, format!("http://{}/", addr));
+        client.add_measurement(Measurement {
+            kind: Kind::Gauge,
+            measure_time: chrono::Utc::now().into(),
+            value: 1.0,
+            name: "test".into(),
+            source: "test".into(),
+        });
+
+        let result = tokio::time::timeout(REQUEST_TIMEOUT * 5, client.shutdown())
+            .await
+            .expect("shutdown hangs");
+        assert!(result.is_err());
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_background_sends_hold_a_permit() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // accept connections, but never answer.
+        let server = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+
+        let client = Client::new("username", "token", None, format!("http://{}/", addr));
+        for _ in 0..=MAX_MEASURE_MEASUREMENTS_PER_REQUEST {
+            client.add_measurement(Measurement {
+                kind: Kind::Gauge,
+                measure_time: chrono::Utc::now().into(),
+                value: 1.0,
+                name: "test".into(),
+                source: "test".into(),
+            });
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            client.send_permits.available_permits(),
+            MAX_CONCURRENT_SENDS - 1
+        );
+
+        tokio::time::sleep(REQUEST_TIMEOUT * 2).await;
+        assert_eq!(
+            client.send_permits.available_permits(),
+            MAX_CONCURRENT_SENDS
+        );
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_queue_is_kept_while_all_sends_are_pending() {
+        let client = Client::new("username", "token", None, "invalid_endpoint");
+        let permits = client
+            .send_permits
+            .clone()
+            .try_acquire_many_owned(MAX_CONCURRENT_SENDS as u32)
+            .unwrap();
+        let measurement = || Measurement {
+            kind: Kind::Gauge,
+            measure_time: chrono::Utc::now().into(),
+            value: 1.0,
+            name: "test".into(),
+            source: "test".into(),
+        };
+
+        for _ in 0..=MAX_MEASURE_MEASUREMENTS_PER_REQUEST {
+            assert!(client.add_measurement(measurement()));
+        }
+        assert_eq!(client.queue_len(), MAX_MEASURE_MEASUREMENTS_PER_REQUEST + 1);
+
+        drop(permits);
+        assert!(client.add_measurement(measurement()));
+        assert_eq!(client.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failing_sends_open_the_circuit() {
+        let client = Client::new("username", "token", None, "invalid_endpoint");
+        let measurement = || Measurement {
+            kind: Kind::Gauge,
+            measure_time: chrono::Utc::now().into(),
+            value: 1.0,
+            name: "test".into(),
+            source: "test".into(),
+        };
+
+        for _ in 0..CIRCUIT_BREAKER_FAILURE_THRESHOLD {
+            for _ in 0..=MAX_MEASURE_MEASUREMENTS_PER_REQUEST {
+                assert!(client.add_measurement(measurement()));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert!(client.circuit_breaker.is_open());
+        assert!(!client.add_measurement(measurement()));
+        assert_eq!(client.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_background_send_counts_dropped() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .with_status(500)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+
+        let client = Client::new("username", "token", None, server.url() + "/");
+        for _ in 0..=MAX_MEASURE_MEASUREMENTS_PER_REQUEST {
+            client.add_measurement(Measurement {
+                kind: Kind::Gauge,
+                measure_time: chrono::Utc::now().into(),
+                value: 1.0,
+                name: "test".into(),
+                source: "test".into(),
+            });
+        }
+        assert_eq!(client.queue_len(), 0);
+
+        for _ in 0..50 {
+            if client.dropped_measurements() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        mock.assert_async().await;
+        assert_eq!(
+            client.dropped_measurements(),
+            (MAX_MEASURE_MEASUREMENTS_PER_REQUEST + 1) as u64
+        );
+        assert_eq!(client.queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_partially_failed_background_send_counts_unsent() {
+        let mut server = mockito::Server::new_async().await;
+        let failing = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("failing".into()))
+            .with_status(500)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("\"test\"".into()))
+            .create_async()
+            .await;
+
+        let client = Client::new("username", "token", None, server.url() + "/");
+        for i in 0..=MAX_MEASURE_MEASUREMENTS_PER_REQUEST {
+            client.add_measurement(Measurement {
+                kind: Kind::Gauge,
+                measure_time: chrono::Utc::now().into(),
+                value: 1.0,
+                name: if i < MAX_MEASURE_MEASUREMENTS_PER_REQUEST {
+                    "test"
+                } else {
+                    "failing"
+                }
+                .into(),
+                source: "test".into(),
+            });
+        }
+
+        for _ in 0..50 {
+            if client.dropped_measurements() > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        failing.assert_async().await;
+        assert_eq!(client.dropped_measurements(), 1);
     }
 
     #[tokio::test]
@@ -221,6 +1047,8 @@ mod tests {
 
         let m = server
             .mock("POST", "/")
+            .match_header("user-agent", http_client::user_agent().as_str())
+            .match_header("x-request-id", mockito::Matcher::Any)
             .match_request(move |request| {
                 let body: serde_json::Value =
                     serde_json::from_slice(request.body().unwrap()).unwrap();
@@ -252,4 +1080,99 @@ mod tests {
         m.assert_async().await;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_send_in_chunks() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+
+        let chunk_sizes = Arc::new(Mutex::new(Vec::new()));
+        let m = server
+            .mock("POST", "/")
+            .match_request({
+                let chunk_sizes = chunk_sizes.clone();
+                move |request| {
+                    let body: serde_json::Value =
+                        serde_json::from_slice(request.body().unwrap()).unwrap();
+                    chunk_sizes
+                        .lock()
+                        .unwrap()
+                        .push(body["gauges"].as_array().unwrap().len());
+                    true
+                }
+            })
+            .expect(3)
+            .create();
+
+        let measurements: Vec<_> = (0..700)
+            .map(|i| Measurement {
+                kind: Kind::Gauge,
+                measure_time: chrono::Utc::now().into(),
+                value: i as f64,
+                name: "test".into(),
+                source: "test".into(),
+            })
+            .collect();
+
+        Client::send(
+            &reqwest::Client::new(),
+            "username",
+            "token",
+            server.url(),
+            &measurements,
+            TimeResolution::Seconds,
+        )
+        .await?;
+
+        m.assert_async().await;
+        assert_eq!(*chunk_sizes.lock().unwrap(), vec![300, 300, 100]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_continues_after_failed_chunk() {
+        let mut server = mockito::Server::new_async().await;
+
+        let is_failing = |request: &mockito::Request| {
+            String::from_utf8_lossy(request.body().unwrap()).contains("failing")
+        };
+        let failing = server
+            .mock("POST", "/")
+            .match_request(is_failing)
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        let working = server
+            .mock("POST", "/")
+            .match_request(move |request| !is_failing(request))
+            .expect(2)
+            .create_async()
+            .await;
+
+        let measurements: Vec<_> = (0..700)
+            .map(|i| Measurement {
+                kind: Kind::Gauge,
+                measure_time: chrono::Utc::now().into(),
+                value: i as f64,
+                name: if i < 300 { "failing" } else { "test" }.into(),
+                source: "test".into(),
+            })
+            .collect();
+
+        let err = Client::send(
+            &reqwest::Client::new(),
+            "username",
+            "token",
+            server.url(),
+            &measurements,
+            TimeResolution::Seconds,
+        )
+        .await
+        .unwrap_err();
+
+        failing.assert_async().await;
+        working.assert_async().await;
+        assert_eq!(err.unsent, 300);
+        assert_eq!(err.errors.len(), 1);
+    }
 }
@@ -1,24 +1,27 @@
+use anyhow::{bail, Result};
 use chrono::{DateTime, FixedOffset};
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_till1, take_while1, take_while_m_n},
-    character::complete::{char, digit1, multispace0, multispace1, space0, space1, u16},
-    combinator::{all_consuming, map, map_res, opt, recognize, rest, value, verify},
+    character::complete::{char, digit1, multispace0, multispace1, space0, space1, u16, u64},
+    combinator::{all_consuming, eof, map, map_res, opt, recognize, rest, value, verify},
     multi::many1,
-    sequence::{delimited, preceded, tuple},
+    number::complete::double,
+    sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
 use tracing::instrument;
 
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Kind {
+pub enum Kind {
     Heroku,
     App,
 }
 
 #[derive(Debug, PartialEq)]
-pub(crate) struct LogLine<'a> {
+pub struct LogLine<'a> {
     pub timestamp: DateTime<FixedOffset>,
     pub source: &'a str,
     pub kind: Kind,
@@ -28,7 +31,7 @@ pub(crate) struct LogLine<'a> {
 pub(crate) type LogMap<'a> = BTreeMap<&'a str, &'a str>;
 
 #[instrument]
-pub(crate) fn parse_log_line(input: &str) -> IResult<&str, LogLine> {
+pub(crate) fn parse_log_line(input: &str) -> IResult<&str, LogLine<'_>> {
     map(
         tuple((
             preceded(multispace0, digit1),
@@ -47,7 +50,15 @@ pub(crate) fn parse_log_line(input: &str) -> IResult<&str, LogLine> {
                     value(Kind::App, tag("app")),
                 )),
             ),
-            preceded(space1, take_till1(|c: char| c.is_whitespace())),
+            alt((
+                // newer stacks send `app[web.1]`
+                delimited(
+                    tag("["),
+                    take_till1(|c: char| c == ']' || c.is_whitespace()),
+                    tag("]"),
+                ),
+                preceded(space1, take_till1(|c: char| c.is_whitespace())),
+            )),
             preceded(tuple((space1, tag("-"), space0)), rest),
         )),
         |(_, _, timestamp, _, kind, source, text)| LogLine {
@@ -59,6 +70,72 @@ pub(crate) fn parse_log_line(input: &str) -> IResult<&str, LogLine> {
     )(input)
 }
 
+/// level of a JSON app log line, ordered by severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum AppLogLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+    Critical,
+}
+
+impl FromStr for AppLogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input.to_ascii_lowercase().as_str() {
+            "debug" => Ok(AppLogLevel::Debug),
+            "info" => Ok(AppLogLevel::Info),
+            "warning" | "warn" => Ok(AppLogLevel::Warning),
+            "error" => Ok(AppLogLevel::Error),
+            "critical" | "fatal" => Ok(AppLogLevel::Critical),
+            _ => bail!("unknown log level: {}", input),
+        }
+    }
+}
+
+/// an app log line written as JSON object, like
+/// `{"level": "error", "message": "..."}`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct JsonAppLog {
+    pub(crate) level: AppLogLevel,
+    pub(crate) message: String,
+}
+
+#[derive(Deserialize)]
+struct RawJsonAppLog {
+    #[serde(alias = "levelname", alias = "severity")]
+    level: String,
+    #[serde(alias = "msg")]
+    message: String,
+}
+
+/// `None` for lines that aren't JSON, or have no known level or message.
+pub(crate) fn parse_json_app_log(input: &str) -> Option<JsonAppLog> {
+    if !input.starts_with('{') {
+        return None;
+    }
+    let raw: RawJsonAppLog = serde_json::from_str(input).ok()?;
+    Some(JsonAppLog {
+        level: raw.level.parse().ok()?,
+        message: raw.message,
+    })
+}
+
+/// `true` when the octet count in front of a logplex frame is larger than
+/// the rest of the frame, so it was cut off in transit.
+pub(crate) fn is_truncated_frame(input: &str) -> bool {
+    let Ok((frame, length)) = delimited(
+        multispace0,
+        map_res(digit1, |digits: &str| digits.parse::<usize>()),
+        space1::<_, ()>,
+    )(input) else {
+        return false;
+    };
+    frame.len() < length
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct ScalingEvent<'a> {
     pub(crate) proc: &'a str,
@@ -76,7 +153,7 @@ impl<'a> From<&'a OwnedScalingEvent> for ScalingEvent<'a> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct OwnedScalingEvent {
     pub(crate) proc: String,
     pub(crate) count: u16,
@@ -96,7 +173,7 @@ impl<'a> From<&ScalingEvent<'a>> for OwnedScalingEvent {
 /// parses heroku scaling events
 /// format like:
 ///     Scaled to web@4:Standard-1X worker@3:Standard-2X by user heroku.hirefire.api@thermondo.de
-pub(crate) fn parse_scaling_event(input: &str) -> IResult<&str, (Vec<ScalingEvent>, &str)> {
+pub(crate) fn parse_scaling_event(input: &str) -> IResult<&str, (Vec<ScalingEvent<'_>>, &str)> {
     map(
         tuple((
             preceded(multispace0, tag("Scaled to")),
@@ -111,7 +188,7 @@ pub(crate) fn parse_scaling_event(input: &str) -> IResult<&str, (Vec<ScalingEven
 /// parses single scaling element
 /// format like:
 ///     web@4:Standard-1X
-fn parse_single_scaling_event(input: &str) -> IResult<&str, ScalingEvent> {
+fn parse_single_scaling_event(input: &str) -> IResult<&str, ScalingEvent<'_>> {
     map(
         tuple((
             take_till1(|c: char| c == '@'),
@@ -124,6 +201,49 @@ fn parse_single_scaling_event(input: &str) -> IResult<&str, ScalingEvent> {
     )(input)
 }
 
+/// names of the heroku error codes, for log lines without description.
+///
+/// see https://devcenter.heroku.com/articles/error-codes
+const HEROKU_ERROR_NAMES: &[(&str, &str)] = &[
+    ("H10", "App crashed"),
+    ("H11", "Backlog too deep"),
+    ("H12", "Request timeout"),
+    ("H13", "Connection closed without response"),
+    ("H14", "No web dynos running"),
+    ("H15", "Idle connection"),
+    ("H16", "Redirect to herokuapp.com"),
+    ("H17", "Poorly formatted HTTP response"),
+    ("H18", "Server Request Interrupted"),
+    ("H19", "Backend connection timeout"),
+    ("H20", "App boot timeout"),
+    ("H21", "Backend connection refused"),
+    ("H22", "Connection limit reached"),
+    ("H23", "Endpoint misconfigured"),
+    ("H24", "Forced close"),
+    ("H25", "HTTP Restriction"),
+    ("H26", "Request Error"),
+    ("H27", "Client Request Interrupted"),
+    ("H28", "Client Connection Idle"),
+    ("R10", "Boot timeout"),
+    ("R12", "Exit timeout"),
+    ("R13", "Attach error"),
+    ("R14", "Memory quota exceeded"),
+    ("R15", "Memory quota vastly exceeded"),
+    ("R16", "Detached"),
+    ("R17", "Checksum error"),
+    ("L10", "Drain buffer overflow"),
+    ("L11", "Tail buffer overflow"),
+    ("L12", "Local buffer overflow"),
+];
+
+/// the name of a heroku error code, like `Request timeout` for `H12`.
+pub(crate) fn heroku_error_name(code: &str) -> Option<&'static str> {
+    HEROKU_ERROR_NAMES
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map(|(_, name)| *name)
+}
+
 /// parses dyno log messages
 /// format like:
 ///     Error R10 (Boot timeout) -> Web process failed to bind to $PORT within 60 seconds of launch
@@ -144,13 +264,66 @@ pub(crate) fn parse_dyno_error_code(input: &str) -> IResult<&str, (&str, &str)>
     )(input)
 }
 
-pub(crate) fn parse_key_value_pairs(input: &str) -> IResult<&str, LogMap> {
+/// parses the start of a dyno process, returns the command.
+/// format like:
+///     Starting process with command `bundle exec puma`
+pub(crate) fn parse_process_start(input: &str) -> IResult<&str, &str> {
+    preceded(
+        tuple((multispace0, tag("Starting process with command"), space1)),
+        rest,
+    )(input)
+}
+
+/// parses the stop of all dyno processes, returns the signal.
+/// format like:
+///     Stopping all processes with SIGTERM
+pub(crate) fn parse_process_stop(input: &str) -> IResult<&str, &str> {
+    all_consuming(delimited(
+        tuple((multispace0, tag("Stopping all processes with"), space1)),
+        take_till1(|c: char| c.is_whitespace()),
+        multispace0,
+    ))(input)
+}
+
+/// parses dyno state changes, returns the old and the new state.
+/// format like:
+///     State changed from starting to up
+pub(crate) fn parse_state_change(input: &str) -> IResult<&str, (&str, &str)> {
+    all_consuming(tuple((
+        preceded(
+            tuple((multispace0, tag("State changed from"), space1)),
+            take_till1(|c: char| c.is_whitespace()),
+        ),
+        delimited(
+            tuple((space1, tag("to"), space1)),
+            take_till1(|c: char| c.is_whitespace()),
+            multispace0,
+        ),
+    )))(input)
+}
+
+/// characters allowed in logfmt keys, see [`parse_key_value_pairs`].
+fn is_key_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '-' || c == '_' || c == '#'
+}
+
+/// cheap check if the text starts with a `key=`, like logfmt does.
+///
+/// [`parse_key_value_pairs`] fails on all other texts, so free-form
+/// lines can skip the full parser.
+pub(crate) fn looks_like_logfmt(input: &str) -> bool {
+    let input = input.trim_start_matches([' ', '\t']);
+    let key_len = input.find(|c: char| !is_key_char(c)).unwrap_or(input.len());
+    key_len > 0 && input[key_len..].starts_with('=')
+}
+
+pub(crate) fn parse_key_value_pairs(input: &str) -> IResult<&str, LogMap<'_>> {
     map(
         many1(map(
             delimited(
                 space0,
                 tuple((
-                    take_while1(|c: char| c.is_alphanumeric() || c == '-' || c == '_' || c == '#'),
+                    take_while1(is_key_char),
                     tag("="),
                     alt((
                         delimited(tag("\""), take_till1(|c: char| c == '"'), tag("\"")),
@@ -165,6 +338,117 @@ pub(crate) fn parse_key_value_pairs(input: &str) -> IResult<&str, LogMap> {
     )(input)
 }
 
+/// unit of a numeric value in a log line, like the `MB` in
+/// `sample#memory_total=221.47MB`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum MetricUnit {
+    None,
+    Bytes,
+    Kilobytes,
+    Megabytes,
+    Gigabytes,
+    Pages,
+    Percent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MetricValue {
+    pub value: f64,
+    pub unit: MetricUnit,
+}
+
+impl MetricValue {
+    /// the value in bytes, for memory units.
+    /// Heroku uses binary multiples, so `1MB` are `1024 * 1024` bytes.
+    pub(crate) fn to_bytes(self) -> Option<f64> {
+        let factor = match self.unit {
+            MetricUnit::Bytes => 1.0,
+            MetricUnit::Kilobytes => 1024.0,
+            MetricUnit::Megabytes => 1024.0 * 1024.0,
+            MetricUnit::Gigabytes => 1024.0 * 1024.0 * 1024.0,
+            MetricUnit::None | MetricUnit::Pages | MetricUnit::Percent => return None,
+        };
+        Some(self.value * factor)
+    }
+}
+
+/// parse a numeric value with an optional unit,
+/// like `221.47MB`, `4709591bytes`, `149293pages`, `12.5%` or `42`.
+pub(crate) fn parse_metric_value(input: &str) -> IResult<&str, MetricValue> {
+    all_consuming(map(
+        tuple((
+            double,
+            alt((
+                value(MetricUnit::Bytes, tag("bytes")),
+                value(MetricUnit::Kilobytes, tag("kB")),
+                value(MetricUnit::Megabytes, tag("MB")),
+                value(MetricUnit::Gigabytes, tag("GB")),
+                value(MetricUnit::Pages, tag("pages")),
+                value(MetricUnit::Percent, tag("%")),
+                value(MetricUnit::None, eof),
+            )),
+        )),
+        |(value, unit)| MetricValue { value, unit },
+    ))(input)
+}
+
+/// parse a duration in milliseconds from router log lines, like `30000ms`.
+pub(crate) fn parse_duration_ms(input: &str) -> IResult<&str, u64> {
+    all_consuming(terminated(u64, tag("ms")))(input)
+}
+
+/// the fields of a router log line we use, from its logfmt pairs.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RouterLog<'a> {
+    /// `info`, `warning` or `error`.
+    pub(crate) at: &'a str,
+    pub(crate) code: Option<&'a str>,
+    pub(crate) desc: Option<&'a str>,
+    pub(crate) method: Option<&'a str>,
+    pub(crate) path: Option<&'a str>,
+    pub(crate) host: Option<&'a str>,
+    pub(crate) request_id: Option<&'a str>,
+    pub(crate) dyno: Option<&'a str>,
+    pub(crate) status: Option<u16>,
+    pub(crate) service_ms: Option<u64>,
+    /// size of the response in bytes.
+    pub(crate) bytes: Option<f64>,
+    /// `http` or `https`.
+    pub(crate) protocol: Option<&'a str>,
+}
+
+impl<'a> TryFrom<&LogMap<'a>> for RouterLog<'a> {
+    type Error = anyhow::Error;
+
+    /// fails only without `at`, other fields are `None` when
+    /// they are missing or invalid.
+    fn try_from(map: &LogMap<'a>) -> Result<Self> {
+        let Some(at) = map.get("at") else {
+            bail!("missing `at` in router log line");
+        };
+        Ok(RouterLog {
+            at,
+            code: map.get("code").copied(),
+            desc: map.get("desc").copied(),
+            method: map.get("method").copied(),
+            path: map.get("path").copied(),
+            host: map.get("host").copied(),
+            request_id: map.get("request_id").copied(),
+            dyno: map.get("dyno").copied(),
+            status: map.get("status").and_then(|status| status.parse().ok()),
+            service_ms: map
+                .get("service")
+                .and_then(|service| parse_duration_ms(service).ok())
+                .map(|(_, service_ms)| service_ms),
+            bytes: map
+                .get("bytes")
+                .and_then(|bytes| parse_metric_value(bytes).ok())
+                .map(|(_, bytes)| bytes.to_bytes().unwrap_or(bytes.value)),
+            protocol: map.get("protocol").copied(),
+        })
+    }
+}
+
 pub(crate) fn parse_sfid(input: &str) -> IResult<&str, &str> {
     verify(
         alt((
@@ -333,6 +617,25 @@ mod tests {
             });
     }
 
+    #[test_case("2022-12-05T08:59:21.850424+00:00"; "fractional seconds")]
+    #[test_case("2022-12-05T08:59:21+00:00"; "without fractional seconds")]
+    #[test_case("2022-12-05T08:59:21.850424Z"; "zulu")]
+    #[test_case("2022-12-05T08:59:21Z"; "zulu without fractional seconds")]
+    #[test_case("2022-12-05T08:59:21z"; "lowercase zulu")]
+    #[test_case("2022-12-05T09:59:21+01:00"; "offset")]
+    fn test_parse_log_line_timestamp_formats(timestamp: &str) {
+        let input = format!("100 <134>1 {} host heroku web.1 - some text", timestamp);
+
+        let (remainder, result) = parse_log_line(&input).expect("parse error");
+        assert!(remainder.is_empty());
+        assert_eq!(
+            result.timestamp,
+            DateTime::parse_from_rfc3339(timestamp).unwrap()
+        );
+        assert_eq!(result.timestamp.timestamp(), 1670230761);
+        assert_eq!(result.text, "some text");
+    }
+
     #[test]
     fn test_full_web_line_info() {
         // 205 <134>1 2022-12-05T09:51:04.778759+00:00 host heroku web.1 - source=web.1 dyno=heroku.261104379.cd817c77-4f8e-4e68-b42a-3dea4e04d99c sample#load_avg_1m=0.00 sample#load_avg_5m=0.00 sample#load_avg_15m=0.01\n337 <134>1 2022-12-05T09:51:04.835127+00:00 host heroku web.1 - source=web.1 dyno=heroku.261104379.cd817c77-4f8e-4e68-b42a-3dea4e04d99c sample#memory_total=221.47MB sample#memory_rss=217.77MB sample#memory_cache=3.70MB sample#memory_swap=0.00MB sample#memory_pgpgin=149293pages sample#memory_pgpgout=123257pages sample#memory_quota=512.00MB\n
@@ -355,6 +658,108 @@ mod tests {
             });
     }
 
+    #[test_case(r#"{"level": "error", "message": "boom"}"#, Some(AppLogLevel::Error); "level")]
+    #[test_case(r#"{"levelname": "WARNING", "msg": "boom"}"#, Some(AppLogLevel::Warning); "python names")]
+    #[test_case(r#"{"severity": "fatal", "message": "boom"}"#, Some(AppLogLevel::Critical); "alias")]
+    #[test_case(r#"{"level": "loud", "message": "boom"}"#, None; "unknown level")]
+    #[test_case(r#"{"level": "error"}"#, None; "missing message")]
+    #[test_case("level=error message=boom", None; "not json")]
+    fn test_parse_json_app_log(input: &str, expected_level: Option<AppLogLevel>) {
+        assert_eq!(
+            parse_json_app_log(input),
+            expected_level.map(|level| JsonAppLog {
+                level,
+                message: "boom".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_app_log_level_ordering() {
+        assert!(AppLogLevel::Debug < AppLogLevel::Info);
+        assert!(AppLogLevel::Info < AppLogLevel::Warning);
+        assert!(AppLogLevel::Warning < AppLogLevel::Error);
+        assert!(AppLogLevel::Error < AppLogLevel::Critical);
+    }
+
+    #[test]
+    fn test_router_log_from_log_map() {
+        let (_, map) = parse_key_value_pairs(
+            "at=error code=H12 desc=\"Request timeout\" method=GET path=/ \
+            host=myapp.herokuapp.com request_id=8601b555 dyno=web.1 \
+            connect=0ms service=30000ms status=503 bytes=1kB protocol=https",
+        )
+        .unwrap();
+
+        assert_eq!(
+            RouterLog::try_from(&map).unwrap(),
+            RouterLog {
+                at: "error",
+                code: Some("H12"),
+                desc: Some("Request timeout"),
+                method: Some("GET"),
+                path: Some("/"),
+                host: Some("myapp.herokuapp.com"),
+                request_id: Some("8601b555"),
+                dyno: Some("web.1"),
+                status: Some(503),
+                service_ms: Some(30000),
+                bytes: Some(1024.0),
+                protocol: Some("https"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_router_log_with_invalid_fields() {
+        let (_, map) = parse_key_value_pairs("at=info status=abc service=slow bytes=many").unwrap();
+
+        assert_eq!(
+            RouterLog::try_from(&map).unwrap(),
+            RouterLog {
+                at: "info",
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_router_log_without_at() {
+        let (_, map) = parse_key_value_pairs("code=H12 path=/").unwrap();
+        assert!(RouterLog::try_from(&map).is_err());
+    }
+
+    #[test_case("app web.1"; "separate source")]
+    #[test_case("app[web.1]"; "bracketed source")]
+    fn test_source_formats(kind_and_source: &str) {
+        let input = format!(
+            "83 <190>1 2022-12-05T08:59:21.66229+00:00 host {} - some text",
+            kind_and_source
+        );
+
+        let (remainder, result) = parse_log_line(&input).expect("parse error");
+        assert!(remainder.is_empty());
+        assert_eq!(
+            result,
+            LogLine {
+                timestamp: DateTime::parse_from_rfc3339("2022-12-05T08:59:21.66229+00:00").unwrap(),
+                kind: Kind::App,
+                source: "web.1",
+                text: "some text",
+            }
+        );
+    }
+
+    #[test_case("app[]"; "empty brackets")]
+    #[test_case("app[web.1"; "unclosed bracket")]
+    fn test_invalid_bracketed_source(kind_and_source: &str) {
+        let input = format!(
+            "83 <190>1 2022-12-05T08:59:21.66229+00:00 host {} - some text",
+            kind_and_source
+        );
+        assert!(parse_log_line(&input).is_err());
+    }
+
     #[test]
     fn test_full_boot_timeout_line_info() {
         let input = "
@@ -476,6 +881,20 @@ mod tests {
         );
     }
 
+    #[test_case("at=info status=200", true; "logfmt")]
+    #[test_case("  sample#memory_rss=1MB", true; "leading spaces")]
+    #[test_case("Traceback (most recent call last):", false; "free text")]
+    #[test_case("Error happened env=staging", false; "pair later in the line")]
+    #[test_case("=value", false; "empty key")]
+    #[test_case("", false; "empty")]
+    fn test_looks_like_logfmt(input: &str, expected: bool) {
+        assert_eq!(looks_like_logfmt(input), expected);
+        // the parser never succeeds where the check says no.
+        if !expected {
+            assert!(parse_key_value_pairs(input).is_err());
+        }
+    }
+
     #[test]
     fn test_pure_text_log_as_key_value_errors() {
         let input: &str = "just some text";
@@ -523,6 +942,96 @@ mod tests {
         );
     }
 
+    #[test_case(
+        "Starting process with command `bundle exec puma`",
+        Some("`bundle exec puma`")
+    )]
+    #[test_case("Starting process", None)]
+    #[test_case("State changed from starting to up", None)]
+    fn test_parse_process_start(input: &str, expected: Option<&str>) {
+        assert_eq!(
+            parse_process_start(input).ok().map(|(_, command)| command),
+            expected
+        );
+    }
+
+    #[test_case("Stopping all processes with SIGTERM", Some("SIGTERM"))]
+    #[test_case("Stopping all processes with SIGKILL", Some("SIGKILL"))]
+    #[test_case("Stopping all processes", None)]
+    #[test_case("Stopping all processes with SIGTERM and more", None)]
+    fn test_parse_process_stop(input: &str, expected: Option<&str>) {
+        assert_eq!(
+            parse_process_stop(input).ok().map(|(_, signal)| signal),
+            expected
+        );
+    }
+
+    #[test_case("State changed from starting to up", Some(("starting", "up")))]
+    #[test_case("State changed from up to down", Some(("up", "down")))]
+    #[test_case("State changed from starting", None)]
+    #[test_case("State changed from starting to up and more", None)]
+    fn test_parse_state_change(input: &str, expected: Option<(&str, &str)>) {
+        assert_eq!(
+            parse_state_change(input).ok().map(|(_, states)| states),
+            expected
+        );
+    }
+
+    #[test_case("30000ms", Some(30000))]
+    #[test_case("0ms", Some(0))]
+    #[test_case("30000", None)]
+    #[test_case("30s", None)]
+    #[test_case("-1ms", None)]
+    #[test_case("", None)]
+    fn test_parse_duration_ms(input: &str, expected: Option<u64>) {
+        assert_eq!(parse_duration_ms(input).ok().map(|(_, ms)| ms), expected);
+    }
+
+    #[test_case("10 <158>1 ", true; "cut off")]
+    #[test_case("10 <158>1 2022", false; "complete")]
+    #[test_case("10 <158>1 2022-12-05", false; "longer than count")]
+    #[test_case("<158>1 2022-12-05", false; "without count")]
+    #[test_case("", false; "empty")]
+    fn test_is_truncated_frame(input: &str, expected: bool) {
+        assert_eq!(is_truncated_frame(input), expected);
+    }
+
+    #[test_case("42", 42.0, MetricUnit::None)]
+    #[test_case("0.01", 0.01, MetricUnit::None)]
+    #[test_case("-1.5", -1.5, MetricUnit::None)]
+    #[test_case("221.47MB", 221.47, MetricUnit::Megabytes)]
+    #[test_case("0.00MB", 0.0, MetricUnit::Megabytes)]
+    #[test_case("2GB", 2.0, MetricUnit::Gigabytes)]
+    #[test_case("512kB", 512.0, MetricUnit::Kilobytes)]
+    #[test_case("4709591bytes", 4709591.0, MetricUnit::Bytes)]
+    #[test_case("149293pages", 149293.0, MetricUnit::Pages)]
+    #[test_case("12.5%", 12.5, MetricUnit::Percent)]
+    fn test_parse_metric_value(input: &str, value: f64, unit: MetricUnit) {
+        let (remainder, result) = parse_metric_value(input).expect("parse error");
+        assert!(remainder.is_empty(), "{}", remainder);
+        assert_eq!(result, MetricValue { value, unit });
+    }
+
+    #[test_case(""; "empty string")]
+    #[test_case("MB"; "missing number")]
+    #[test_case("12TB"; "unknown unit")]
+    #[test_case("12 MB"; "space before unit")]
+    #[test_case("12MBs"; "text after unit")]
+    fn test_parse_metric_value_fails(input: &str) {
+        assert!(parse_metric_value(input).is_err());
+    }
+
+    #[test_case(MetricUnit::Bytes, Some(1.0))]
+    #[test_case(MetricUnit::Kilobytes, Some(1024.0))]
+    #[test_case(MetricUnit::Megabytes, Some(1024.0 * 1024.0))]
+    #[test_case(MetricUnit::Gigabytes, Some(1024.0 * 1024.0 * 1024.0))]
+    #[test_case(MetricUnit::None, None)]
+    #[test_case(MetricUnit::Pages, None)]
+    #[test_case(MetricUnit::Percent, None)]
+    fn test_metric_value_to_bytes(unit: MetricUnit, expected: Option<f64>) {
+        assert_eq!(MetricValue { value: 1.0, unit }.to_bytes(), expected);
+    }
+
     #[test_case("R10", "Boot timeout", "Error R10 (Boot timeout) -> Web process failed to bind to $PORT within 60 seconds of launch")]
     #[test_case(
         "R12",
@@ -549,6 +1058,15 @@ mod tests {
         assert_eq!(name, expected_name);
     }
 
+    #[test_case("H12", Some("Request timeout"))]
+    #[test_case("R14", Some("Memory quota exceeded"))]
+    #[test_case("L10", Some("Drain buffer overflow"))]
+    #[test_case("H99", None)]
+    #[test_case("h12", None)]
+    fn test_heroku_error_name(code: &str, expected: Option<&str>) {
+        assert_eq!(heroku_error_name(code), expected);
+    }
+
     #[test_case(
         vec![ScalingEvent {proc: "web", count: 4, size: "Standard-1X"}],
         "heroku.hirefire.api@thermondo.de",
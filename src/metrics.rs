@@ -1,40 +1,569 @@
+use anyhow::{bail, Result};
 use chrono::{DateTime, FixedOffset};
+use std::{borrow::Cow, str::FromStr};
+use tracing::warn;
 
-use crate::{librato, log_parser::ScalingEvent};
+use crate::{
+    librato,
+    log_parser::{parse_metric_value, Kind, LogMap, ScalingEvent},
+};
+
+/// which dyno count metrics we send for scaling events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum DynoCountGrouping {
+    /// `dyno_count.<size>` and `dyno_count`.
+    #[default]
+    Both,
+    /// only `dyno_count.<size>`.
+    Size,
+    /// only `dyno_count`, summed over all sizes of a proc.
+    NoSize,
+}
+
+impl FromStr for DynoCountGrouping {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "both" => Ok(DynoCountGrouping::Both),
+            "size" => Ok(DynoCountGrouping::Size),
+            "no_size" => Ok(DynoCountGrouping::NoSize),
+            _ => bail!("unknown dyno count grouping: {}", input),
+        }
+    }
+}
+
+/// how we handle the case of values from log lines in metric names,
+/// like the dyno size in `dyno_count.standard-1x`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum MetricNameCase {
+    #[default]
+    Lower,
+    /// keep the casing from the log line, like `dyno_count.Standard-1X`.
+    Preserve,
+}
+
+impl FromStr for MetricNameCase {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "lower" => Ok(MetricNameCase::Lower),
+            "preserve" => Ok(MetricNameCase::Preserve),
+            _ => bail!("unknown metric name case: {}", input),
+        }
+    }
+}
+
+impl MetricNameCase {
+    fn apply<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        match self {
+            MetricNameCase::Lower => value.to_lowercase().into(),
+            MetricNameCase::Preserve => value.into(),
+        }
+    }
+}
+
+/// where the measurements of a destination go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum MetricSink {
+    /// the configured librato accounts.
+    #[default]
+    Librato,
+    /// print them to stdout instead, for local development.
+    Stdout,
+}
+
+impl FromStr for MetricSink {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "librato" => Ok(MetricSink::Librato),
+            "stdout" => Ok(MetricSink::Stdout),
+            _ => bail!("unknown metric sink: {}", input),
+        }
+    }
+}
+
+/// captures the measurements of a destination instead of sending them,
+/// so tests can assert them before serialization.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct TestSink {
+    measurements: std::sync::Mutex<Vec<librato::Measurement>>,
+}
+
+#[cfg(test)]
+impl TestSink {
+    pub(crate) fn add(&self, measurement: librato::Measurement) {
+        self.measurements.lock().unwrap().push(measurement);
+    }
+
+    pub(crate) fn fetch_and_clear(&self) -> Vec<librato::Measurement> {
+        std::mem::take(&mut *self.measurements.lock().unwrap())
+    }
+}
+
+/// a gauge for the generators below, invalid measurements like `NaN`
+/// values are logged and skipped.
+fn gauge(
+    timestamp: &DateTime<FixedOffset>,
+    name: impl Into<String>,
+    source: impl Into<String>,
+    value: f64,
+) -> Option<librato::Measurement> {
+    librato::Measurement::gauge(*timestamp, name, source, value)
+        .inspect_err(|err| warn!(?err, "skipping invalid measurement"))
+        .ok()
+}
+
+/// a counter for the generators below, for things that happen per log
+/// line. Gauges with the same name, source and second overwrite each
+/// other in librato, counters add up.
+fn counter(
+    timestamp: &DateTime<FixedOffset>,
+    name: impl Into<String>,
+    source: impl Into<String>,
+) -> Option<librato::Measurement> {
+    librato::Measurement::new(librato::Kind::Counter, *timestamp, name, source, 1.0)
+        .inspect_err(|err| warn!(?err, "skipping invalid measurement"))
+        .ok()
+}
+
+/// generate a librato counter for a scaling event whose dyno counts were
+/// overwritten by a resend, see [`crate::config::Destination::update_scaling_events`].
+pub(crate) fn generate_librato_scaling_conflict_metric(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+) -> Option<librato::Measurement> {
+    counter(
+        timestamp,
+        "scaling.conflict",
+        librato::sanitize_source(source),
+    )
+}
+
+/// generate a librato counter for a router request over plain `http`,
+/// with the requested host as source.
+pub(crate) fn generate_librato_insecure_request_metric(
+    timestamp: &DateTime<FixedOffset>,
+    host: &str,
+) -> Option<librato::Measurement> {
+    counter(
+        timestamp,
+        "router.insecure_requests",
+        librato::sanitize_source(host),
+    )
+}
 
 /// generate librato metrics from scaling events
+///
+/// `grouping` decides if we send the dyno count per size, per proc,
+/// or both. With `include_total`, the total dyno count over all procs
+/// is also sent, with `all` as source. `name_case` applies to the size.
+///
+/// `scaled_by` is the user who triggered the scaling. Since librato
+/// sources can't carry more dimensions, it's sent as separate
+/// `scaling_events` counter with the user as source, so we can tell
+/// autoscaler-driven and manual scaling apart.
 pub(crate) fn generate_librato_scaling_metrics(
     timestamp: &DateTime<FixedOffset>,
     events: &[ScalingEvent<'_>],
+    include_total: bool,
+    grouping: DynoCountGrouping,
+    name_case: MetricNameCase,
+    scaled_by: Option<&str>,
 ) -> Vec<librato::Measurement> {
-    let mut result = Vec::with_capacity(events.len() * 2);
-
-    for event in events {
-        result.push(librato::Measurement {
-            measure_time: *timestamp,
-            kind: librato::Kind::Gauge,
-            value: event.count as f64,
-            source: event.proc.to_string(),
-            name: format!("dyno_count.{}", event.size.to_lowercase()),
-        });
-        result.push(librato::Measurement {
-            measure_time: *timestamp,
-            kind: librato::Kind::Gauge,
-            value: event.count as f64,
-            source: event.proc.to_string(),
-            name: "dyno_count".to_string(),
-        });
+    let mut result = Vec::with_capacity(events.len() * 2 + 2);
+
+    if let Some(user) = scaled_by {
+        result.extend(counter(
+            timestamp,
+            "scaling_events",
+            librato::sanitize_source(user),
+        ));
+    }
+
+    for (i, event) in events.iter().enumerate() {
+        if grouping != DynoCountGrouping::NoSize {
+            result.extend(gauge(
+                timestamp,
+                librato::sanitize_name(&format!("dyno_count.{}", name_case.apply(event.size))),
+                librato::sanitize_source(event.proc),
+                event.count as f64,
+            ));
+        }
+
+        // only once per proc, at its first event.
+        if grouping != DynoCountGrouping::Size
+            && !events[..i].iter().any(|other| other.proc == event.proc)
+        {
+            result.extend(gauge(
+                timestamp,
+                "dyno_count",
+                librato::sanitize_source(event.proc),
+                events
+                    .iter()
+                    .filter(|other| other.proc == event.proc)
+                    .map(|other| other.count as f64)
+                    .sum(),
+            ));
+        }
+    }
+
+    if include_total {
+        result.extend(gauge(
+            timestamp,
+            "dyno_count",
+            "all",
+            events.iter().map(|event| event.count as f64).sum(),
+        ));
     }
 
     result
 }
 
+/// generate librato metrics for the response size of a single router request.
+pub(crate) fn generate_librato_response_bytes_metric(
+    timestamp: &DateTime<FixedOffset>,
+    route: &str,
+    bytes: f64,
+) -> Option<librato::Measurement> {
+    gauge(
+        timestamp,
+        "router.response_bytes",
+        librato::sanitize_source(route),
+        bytes,
+    )
+}
+
+/// HTTP methods we break the timeout metrics down by, others are skipped
+/// to bound the amount of metrics.
+const HTTP_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+
+/// generate librato metrics for how long the router waited for a response
+/// before a request timeout (H12).
+///
+/// With a known `method`, the same value is also sent per method, like
+/// `router.timeout_service_ms.post`.
+pub(crate) fn generate_librato_timeout_service_metrics(
+    timestamp: &DateTime<FixedOffset>,
+    route: &str,
+    method: Option<&str>,
+    service_ms: u64,
+) -> Vec<librato::Measurement> {
+    let source = librato::sanitize_source(route);
+    let mut names = vec!["router.timeout_service_ms".to_owned()];
+    if let Some(method) = method.filter(|method| HTTP_METHODS.contains(method)) {
+        names.push(format!(
+            "router.timeout_service_ms.{}",
+            method.to_lowercase()
+        ));
+    }
+
+    names
+        .into_iter()
+        .filter_map(|name| gauge(timestamp, name, source.clone(), service_ms as f64))
+        .collect()
+}
+
+/// generate librato metrics for the time a dyno needed from starting
+/// the process until it was up.
+pub(crate) fn generate_librato_boot_duration_metric(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+    boot_duration_ms: i64,
+) -> Option<librato::Measurement> {
+    gauge(
+        timestamp,
+        "dyno.boot_duration_ms",
+        librato::sanitize_source(source),
+        boot_duration_ms as f64,
+    )
+}
+
+/// make a heroku sample key usable as metric name, like
+/// `sample#memory_rss` -> `sample.memory_rss`, since `#` isn't allowed
+/// in names. Without a separator the `#` is removed.
+pub(crate) fn normalize_sample_key(key: &str, separator: Option<char>) -> String {
+    match separator {
+        Some(separator) => key.replace('#', &separator.to_string()),
+        None => key.replace('#', ""),
+    }
+}
+
+/// generate librato metrics from heroku runtime metrics, like
+/// `sample#memory_rss=217.77MB`. Memory values are sent in bytes,
+/// values we can't parse are skipped.
+pub(crate) fn generate_librato_sample_metrics(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+    map: &LogMap,
+    separator: Option<char>,
+) -> Vec<librato::Measurement> {
+    sample_gauges(timestamp, source, map, |key| {
+        normalize_sample_key(key, separator)
+    })
+}
+
+/// generate librato metrics from the `sample#` metrics of an addon,
+/// like `sample#db_size=4709591bytes` from `heroku-postgres`, named
+/// after the addon without the `heroku-` prefix: `postgres.db_size`.
+pub(crate) fn generate_librato_addon_metrics(
+    timestamp: &DateTime<FixedOffset>,
+    addon: &str,
+    source: &str,
+    map: &LogMap,
+) -> Vec<librato::Measurement> {
+    let prefix = addon.strip_prefix("heroku-").unwrap_or(addon);
+    sample_gauges(timestamp, source, map, |key| {
+        format!("{}.{}", prefix, key.trim_start_matches("sample#"))
+    })
+}
+
+fn sample_gauges(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+    map: &LogMap,
+    name: impl Fn(&str) -> String,
+) -> Vec<librato::Measurement> {
+    map.iter()
+        .filter(|(key, _)| key.starts_with("sample#"))
+        .filter_map(|(key, value)| {
+            let (_, metric_value) = parse_metric_value(value).ok()?;
+            gauge(
+                timestamp,
+                librato::sanitize_name(&name(key)),
+                librato::sanitize_source(source),
+                metric_value.to_bytes().unwrap_or(metric_value.value),
+            )
+        })
+        .collect()
+}
+
+/// generate a librato counter for an empty log line, which dynos send
+/// even when they are quiet, so we know they are alive.
+pub(crate) fn generate_librato_heartbeat_metric(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+) -> Option<librato::Measurement> {
+    counter(
+        timestamp,
+        "dyno.heartbeat",
+        librato::sanitize_source(source),
+    )
+}
+
+/// generate a librato counter for a dyno lifecycle event, like `starting`,
+/// to use as deploy & restart markers.
+pub(crate) fn generate_librato_lifecycle_metric(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+    event: &str,
+) -> Option<librato::Measurement> {
+    counter(
+        timestamp,
+        format!("dyno.lifecycle.{}", event),
+        librato::sanitize_source(source),
+    )
+}
+
+/// generate librato metrics for a log line that was repeated
+/// `count` times in a row, and only handled once.
+pub(crate) fn generate_librato_repeated_lines_metric(
+    timestamp: &DateTime<FixedOffset>,
+    source: &str,
+    count: usize,
+) -> Option<librato::Measurement> {
+    gauge(
+        timestamp,
+        "repeated_lines",
+        librato::sanitize_source(source),
+        count as f64,
+    )
+}
+
+/// generate a librato counter for a router error.
+pub(crate) fn generate_librato_router_error_metric(
+    timestamp: &DateTime<FixedOffset>,
+    code: &str,
+) -> Option<librato::Measurement> {
+    counter(timestamp, "router.errors", librato::sanitize_source(code))
+}
+
+/// generate a librato counter for a router warning.
+pub(crate) fn generate_librato_router_warning_metric(
+    timestamp: &DateTime<FixedOffset>,
+    code: &str,
+) -> Option<librato::Measurement> {
+    counter(timestamp, "router.warnings", librato::sanitize_source(code))
+}
+
+/// generate the heartbeat metric, which is regularly sent
+/// as long as the service is running.
+pub(crate) fn generate_librato_alive_metric(
+    timestamp: &DateTime<FixedOffset>,
+) -> Option<librato::Measurement> {
+    gauge(timestamp, "logreporter.alive", "log-reporter", 1.0)
+}
+
+/// the value at the given percentile (0-100), using the nearest-rank method.
+/// Sorts `values` in place.
+pub(crate) fn percentile(values: &mut [f64], percentile: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let rank = ((percentile / 100.0) * values.len() as f64).ceil() as usize;
+    Some(values[rank.clamp(1, values.len()) - 1])
+}
+
+/// generate librato metrics for the 95th percentile of the drain lag
+/// in a batch, the time between the log line and us receiving it.
+pub(crate) fn generate_librato_drain_lag_metric(
+    timestamp: &DateTime<FixedOffset>,
+    lag_ms: f64,
+) -> Option<librato::Measurement> {
+    gauge(timestamp, "drain_lag_ms", "p95", lag_ms)
+}
+
+/// generate a librato metric for the amount of distinct sentry fingerprints
+/// in a batch. A spike hints at a broad outage instead of a single hot error.
+pub(crate) fn generate_librato_distinct_fingerprints_metric(
+    timestamp: &DateTime<FixedOffset>,
+    count: usize,
+) -> Option<librato::Measurement> {
+    gauge(
+        timestamp,
+        "sentry.distinct_fingerprints",
+        "batch",
+        count as f64,
+    )
+}
+
+/// generate librato metrics for the percentiles of our processing time
+/// per event in a batch, from parsing the line until the sentry capture
+/// or the measurement is queued.
+pub(crate) fn generate_librato_event_latency_metrics(
+    timestamp: &DateTime<FixedOffset>,
+    latencies_ms: &mut [f64],
+) -> Vec<librato::Measurement> {
+    [("p50", 50.0), ("p95", 95.0), ("p99", 99.0)]
+        .into_iter()
+        .filter_map(|(source, p)| {
+            gauge(
+                timestamp,
+                "event_latency_ms",
+                source,
+                percentile(latencies_ms, p)?,
+            )
+        })
+        .collect()
+}
+
+/// generate a librato metric for the amount of configured destinations,
+/// to verify the config after a deploy.
+pub(crate) fn generate_librato_destinations_loaded_metric(
+    timestamp: &DateTime<FixedOffset>,
+    count: usize,
+) -> Option<librato::Measurement> {
+    gauge(
+        timestamp,
+        "destinations_loaded",
+        "log-reporter",
+        count as f64,
+    )
+}
+
+/// generate a librato counter for the log lines logplex declared
+/// in a batch, but we didn't parse.
+pub(crate) fn generate_librato_lines_unparsed_metric(
+    timestamp: &DateTime<FixedOffset>,
+    count: usize,
+) -> Option<librato::Measurement> {
+    counter(timestamp, "lines_unparsed_total", "logplex").map(|measurement| librato::Measurement {
+        value: count as f64,
+        ..measurement
+    })
+}
+
+/// generate librato metrics for the amount of log lines per kind
+/// in a single batch.
+pub(crate) fn generate_librato_line_count_metrics(
+    timestamp: &DateTime<FixedOffset>,
+    counts: &[(Kind, usize)],
+) -> Vec<librato::Measurement> {
+    counts
+        .iter()
+        .filter_map(|(kind, count)| {
+            let source = match kind {
+                Kind::Heroku => "heroku",
+                Kind::App => "app",
+            };
+            gauge(timestamp, "log_lines", source, *count as f64)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
-    use self::librato::{Kind, Measurement};
+    use self::librato::Measurement;
 
     use super::*;
     use chrono::Local;
+    use test_case::test_case;
+
+    #[test_case(&[], 95.0, None; "empty")]
+    #[test_case(&[1.0], 95.0, Some(1.0); "single value")]
+    #[test_case(&[3.0, 1.0, 2.0], 50.0, Some(2.0); "median")]
+    #[test_case(&[3.0, 1.0, 2.0], 100.0, Some(3.0); "max")]
+    #[test_case(&[3.0, 1.0, 2.0], 0.0, Some(1.0); "min")]
+    fn test_percentile(values: &[f64], p: f64, expected: Option<f64>) {
+        assert_eq!(percentile(&mut values.to_vec(), p), expected);
+    }
+
+    #[test]
+    fn test_percentile_of_100_values() {
+        let mut values: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+        assert_eq!(percentile(&mut values, 95.0), Some(95.0));
+    }
+
+    #[test]
+    fn test_generate_librato_event_latency_metrics() {
+        let ts = Local::now().fixed_offset();
+        let mut latencies: Vec<f64> = (1..=100).rev().map(f64::from).collect();
+
+        let result: Vec<_> = generate_librato_event_latency_metrics(&ts, &mut latencies)
+            .into_iter()
+            .map(|m| (m.name, m.source, m.value))
+            .collect();
+        assert_eq!(
+            result,
+            vec![
+                ("event_latency_ms".into(), "p50".into(), 50.0),
+                ("event_latency_ms".into(), "p95".into(), 95.0),
+                ("event_latency_ms".into(), "p99".into(), 99.0),
+            ]
+        );
+
+        assert!(generate_librato_event_latency_metrics(&ts, &mut []).is_empty());
+    }
+
+    #[test]
+    fn test_generate_librato_scaling_conflict_metric() {
+        let ts = Local::now().fixed_offset();
+        assert_eq!(
+            generate_librato_scaling_conflict_metric(&ts, "api"),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "scaling.conflict".into(),
+                value: 1.0,
+                source: "api".into()
+            })
+        );
+    }
 
     #[test]
     fn test_generate_librato_scaling_metrics() {
@@ -46,6 +575,10 @@ mod tests {
                 count: 99,
                 size: "huuuuge-2X",
             }],
+            false,
+            DynoCountGrouping::Both,
+            MetricNameCase::Lower,
+            None,
         );
 
         assert_eq!(
@@ -53,14 +586,14 @@ mod tests {
             vec![
                 Measurement {
                     measure_time: ts,
-                    kind: Kind::Gauge,
+                    kind: librato::Kind::Gauge,
                     name: "dyno_count.huuuuge-2x".into(),
                     value: 99.0,
                     source: "web".into()
                 },
                 Measurement {
                     measure_time: ts,
-                    kind: Kind::Gauge,
+                    kind: librato::Kind::Gauge,
                     name: "dyno_count".into(),
                     value: 99.0,
                     source: "web".into()
@@ -68,4 +601,467 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_generate_librato_scaling_metrics_with_total() {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_scaling_metrics(
+            &ts,
+            &[
+                ScalingEvent {
+                    proc: "web",
+                    count: 3,
+                    size: "Standard-1X",
+                },
+                ScalingEvent {
+                    proc: "worker",
+                    count: 2,
+                    size: "Standard-2X",
+                },
+            ],
+            true,
+            DynoCountGrouping::Both,
+            MetricNameCase::Lower,
+            None,
+        );
+
+        assert_eq!(result.len(), 5);
+        assert_eq!(
+            result.last().unwrap(),
+            &Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "dyno_count".into(),
+                value: 5.0,
+                source: "all".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_scaling_metrics_with_user() {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_scaling_metrics(
+            &ts,
+            &[ScalingEvent {
+                proc: "web",
+                count: 2,
+                size: "Standard-1X",
+            }],
+            false,
+            DynoCountGrouping::Both,
+            MetricNameCase::Lower,
+            Some("heroku.hirefire.api@thermondo.de"),
+        );
+
+        assert_eq!(result.len(), 3);
+        assert_eq!(
+            result[0],
+            Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "scaling_events".into(),
+                value: 1.0,
+                source: "heroku.hirefire.api_thermondo.de".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_scaling_metrics_sanitizes_names() {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_scaling_metrics(
+            &ts,
+            &[ScalingEvent {
+                proc: "my \"web\"",
+                count: 2,
+                size: "Standard 1X",
+            }],
+            false,
+            DynoCountGrouping::Both,
+            MetricNameCase::Lower,
+            None,
+        );
+
+        assert_eq!(
+            result
+                .iter()
+                .map(|m| (m.name.as_str(), m.source.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("dyno_count.standard_1x", "my__web_"),
+                ("dyno_count", "my__web_")
+            ]
+        );
+    }
+
+    #[test_case(DynoCountGrouping::Both, &[("dyno_count.standard-1x", 2.0), ("dyno_count", 5.0), ("dyno_count.standard-2x", 3.0)]; "both")]
+    #[test_case(DynoCountGrouping::Size, &[("dyno_count.standard-1x", 2.0), ("dyno_count.standard-2x", 3.0)]; "size")]
+    #[test_case(DynoCountGrouping::NoSize, &[("dyno_count", 5.0)]; "no size")]
+    fn test_generate_librato_scaling_metrics_grouping(
+        grouping: DynoCountGrouping,
+        expected: &[(&str, f64)],
+    ) {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_scaling_metrics(
+            &ts,
+            &[
+                ScalingEvent {
+                    proc: "web",
+                    count: 2,
+                    size: "Standard-1X",
+                },
+                ScalingEvent {
+                    proc: "web",
+                    count: 3,
+                    size: "Standard-2X",
+                },
+            ],
+            false,
+            grouping,
+            MetricNameCase::Lower,
+            None,
+        );
+
+        assert_eq!(
+            result
+                .iter()
+                .map(|m| (m.name.as_str(), m.value))
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert!(result.iter().all(|m| m.source == "web"));
+    }
+
+    #[test_case("both", Some(DynoCountGrouping::Both))]
+    #[test_case("size", Some(DynoCountGrouping::Size))]
+    #[test_case("no_size", Some(DynoCountGrouping::NoSize))]
+    #[test_case("other", None)]
+    fn test_parse_dyno_count_grouping(input: &str, expected: Option<DynoCountGrouping>) {
+        assert_eq!(input.parse().ok(), expected);
+    }
+
+    #[test_case(MetricNameCase::Lower, "dyno_count.standard-1x"; "lower")]
+    #[test_case(MetricNameCase::Preserve, "dyno_count.Standard-1X"; "preserve")]
+    fn test_generate_librato_scaling_metrics_name_case(name_case: MetricNameCase, expected: &str) {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_scaling_metrics(
+            &ts,
+            &[ScalingEvent {
+                proc: "web",
+                count: 2,
+                size: "Standard-1X",
+            }],
+            false,
+            DynoCountGrouping::Size,
+            name_case,
+            None,
+        );
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].name, expected);
+    }
+
+    #[test_case("lower", Some(MetricNameCase::Lower))]
+    #[test_case("preserve", Some(MetricNameCase::Preserve))]
+    #[test_case("upper", None)]
+    fn test_parse_metric_name_case(input: &str, expected: Option<MetricNameCase>) {
+        assert_eq!(input.parse().ok(), expected);
+    }
+
+    #[test]
+    fn test_generate_librato_response_bytes_metric() {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_response_bytes_metric(&ts, "/api/{number}/", 15055.0);
+
+        assert_eq!(
+            result,
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "router.response_bytes".into(),
+                value: 15055.0,
+                source: "_api__number__".into()
+            })
+        );
+    }
+
+    #[test_case(f64::NAN; "nan")]
+    #[test_case(f64::INFINITY; "infinite")]
+    fn test_generate_librato_response_bytes_metric_invalid(bytes: f64) {
+        let ts = Local::now().fixed_offset();
+
+        assert!(generate_librato_response_bytes_metric(&ts, "/", bytes).is_none());
+    }
+
+    #[test]
+    fn test_generate_librato_line_count_metrics() {
+        let ts = Local::now().fixed_offset();
+        let result = generate_librato_line_count_metrics(&ts, &[(Kind::Heroku, 3), (Kind::App, 0)]);
+
+        assert_eq!(
+            result,
+            vec![
+                Measurement {
+                    measure_time: ts,
+                    kind: librato::Kind::Gauge,
+                    name: "log_lines".into(),
+                    value: 3.0,
+                    source: "heroku".into()
+                },
+                Measurement {
+                    measure_time: ts,
+                    kind: librato::Kind::Gauge,
+                    name: "log_lines".into(),
+                    value: 0.0,
+                    source: "app".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_router_error_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_router_error_metric(&ts, "H15"),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "router.errors".into(),
+                value: 1.0,
+                source: "H15".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_router_warning_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_router_warning_metric(&ts, "H81"),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "router.warnings".into(),
+                value: 1.0,
+                source: "H81".into()
+            })
+        );
+    }
+
+    #[test_case(None, &["router.timeout_service_ms"]; "without method")]
+    #[test_case(Some("POST"), &["router.timeout_service_ms", "router.timeout_service_ms.post"]; "known method")]
+    #[test_case(Some("BREW"), &["router.timeout_service_ms"]; "unknown method")]
+    fn test_generate_librato_timeout_service_metrics(method: Option<&str>, expected: &[&str]) {
+        let ts = Local::now().fixed_offset();
+
+        let measurements =
+            generate_librato_timeout_service_metrics(&ts, "/api/{number}/", method, 30000);
+
+        assert_eq!(
+            measurements
+                .iter()
+                .map(|m| m.name.as_str())
+                .collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!(
+            measurements[0],
+            Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "router.timeout_service_ms".into(),
+                value: 30000.0,
+                source: "_api__number__".into()
+            }
+        );
+        assert!(measurements
+            .iter()
+            .all(|m| m.source == "_api__number__" && m.value == 30000.0));
+    }
+
+    #[test]
+    fn test_generate_librato_boot_duration_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_boot_duration_metric(&ts, "web.1", 4500),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "dyno.boot_duration_ms".into(),
+                value: 4500.0,
+                source: "web.1".into()
+            })
+        );
+    }
+
+    #[test_case("sample#memory_rss", Some('.'), "sample.memory_rss"; "dot")]
+    #[test_case("sample#memory_rss", Some('_'), "sample_memory_rss"; "underscore")]
+    #[test_case("sample#memory_rss", None, "samplememory_rss"; "removed")]
+    #[test_case("memory_rss", Some('.'), "memory_rss"; "without hash")]
+    fn test_normalize_sample_key(key: &str, separator: Option<char>, expected: &str) {
+        assert_eq!(normalize_sample_key(key, separator), expected);
+    }
+
+    #[test]
+    fn test_generate_librato_sample_metrics() {
+        let ts = Local::now().fixed_offset();
+        let map = LogMap::from_iter([
+            ("source", "web.1"),
+            ("sample#memory_rss", "2.00MB"),
+            ("sample#load_avg_1m", "0.50"),
+            ("sample#broken", "much"),
+        ]);
+
+        assert_eq!(
+            generate_librato_sample_metrics(&ts, "web.1", &map, Some('.')),
+            vec![
+                Measurement {
+                    measure_time: ts,
+                    kind: librato::Kind::Gauge,
+                    name: "sample.load_avg_1m".into(),
+                    value: 0.5,
+                    source: "web.1".into()
+                },
+                Measurement {
+                    measure_time: ts,
+                    kind: librato::Kind::Gauge,
+                    name: "sample.memory_rss".into(),
+                    value: 2.0 * 1024.0 * 1024.0,
+                    source: "web.1".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_addon_metrics() {
+        let ts = Local::now().fixed_offset();
+        let map = LogMap::from_iter([
+            ("source", "DATABASE"),
+            ("addon", "postgresql-curly-12345"),
+            ("sample#db_size", "4709591bytes"),
+            ("sample#active-connections", "8"),
+        ]);
+
+        assert_eq!(
+            generate_librato_addon_metrics(&ts, "heroku-postgres", "DATABASE", &map),
+            vec![
+                Measurement {
+                    measure_time: ts,
+                    kind: librato::Kind::Gauge,
+                    name: "postgres.active-connections".into(),
+                    value: 8.0,
+                    source: "DATABASE".into()
+                },
+                Measurement {
+                    measure_time: ts,
+                    kind: librato::Kind::Gauge,
+                    name: "postgres.db_size".into(),
+                    value: 4709591.0,
+                    source: "DATABASE".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_heartbeat_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_heartbeat_metric(&ts, "dramatiqworker.1"),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "dyno.heartbeat".into(),
+                value: 1.0,
+                source: "dramatiqworker.1".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_lifecycle_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_lifecycle_metric(&ts, "web.1", "stopping"),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "dyno.lifecycle.stopping".into(),
+                value: 1.0,
+                source: "web.1".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_lines_unparsed_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_lines_unparsed_metric(&ts, 3),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Counter,
+                name: "lines_unparsed_total".into(),
+                value: 3.0,
+                source: "logplex".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_destinations_loaded_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_destinations_loaded_metric(&ts, 7),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "destinations_loaded".into(),
+                value: 7.0,
+                source: "log-reporter".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_repeated_lines_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_repeated_lines_metric(&ts, "web.1", 5),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "repeated_lines".into(),
+                value: 5.0,
+                source: "web.1".into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_generate_librato_alive_metric() {
+        let ts = Local::now().fixed_offset();
+
+        assert_eq!(
+            generate_librato_alive_metric(&ts),
+            Some(Measurement {
+                measure_time: ts,
+                kind: librato::Kind::Gauge,
+                name: "logreporter.alive".into(),
+                value: 1.0,
+                source: "log-reporter".into()
+            })
+        );
+    }
 }
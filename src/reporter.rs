@@ -1,232 +1,2153 @@
 use crate::{
-    config::Destination,
+    classifiers::{Action, BatchState, ParsedLine},
+    config::{Destination, DestinationSettings},
     log_parser::{
-        parse_dyno_error_code, parse_key_value_pairs, parse_log_line, parse_offer_extension_number,
-        parse_offer_number, parse_project_reference, parse_scaling_event, parse_sfid, Kind,
-        LogLine, LogMap,
+        heroku_error_name, is_truncated_frame, looks_like_logfmt, parse_json_app_log,
+        parse_key_value_pairs, parse_log_line, AppLogLevel, JsonAppLog, Kind, LogLine, RouterLog,
     },
-    metrics::generate_librato_scaling_metrics,
+    metrics::{
+        generate_librato_distinct_fingerprints_metric, generate_librato_drain_lag_metric,
+        generate_librato_event_latency_metrics, generate_librato_line_count_metrics,
+        generate_librato_repeated_lines_metric, percentile,
+    },
+    routes::{route_from_path, templaters, Templater, TrailingSlash},
 };
 use anyhow::{Context as _, Result};
-use axum::http::uri::Uri;
-use sentry::{Client, Hub, Level, Scope};
-use std::collections::HashMap;
+use axum::http::uri::{PathAndQuery, Uri};
+use chrono::{DateTime, FixedOffset, Utc};
+use sentry::{
+    protocol::{Event, LogEntry},
+    Client, Hub, Level, Scope,
+};
 use std::sync::Arc;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
 use tracing::{debug, info, instrument, warn};
-use uuid::Uuid;
 
-#[derive(Debug)]
-struct SentryMessage {
+#[derive(Debug, Clone)]
+pub struct SentryMessage {
+    level: Level,
+    /// release of the app that logged the line, see `sentry_release`.
+    release: Option<String>,
     tags: HashMap<String, String>,
+    /// additional data, not indexed by sentry, see `sentry_extra_tags`.
+    extra: HashMap<String, String>,
     fingerprint: Vec<String>,
+    /// the message template with `%s` placeholders, sent as sentry `logentry`
+    /// so the events are searchable by template.
     message: String,
+    /// replace the placeholders in `message`, in order.
+    params: Vec<String>,
 }
 
-/// generate a route-name from a URL path.
-/// Replaces elements in the URL that are
-/// - positive integers
-/// - UUIDs
-/// - Salesforce IDs
-/// - thermondo project references
-/// - thermondo offer & offer-extension numbers
-fn route_from_path(path: &str) -> String {
-    let elements: Vec<_> = path
-        .split('/')
-        .map(|el| {
-            if el.parse::<u64>().is_ok() {
-                "{number}"
-            } else if Uuid::try_parse(el).is_ok() {
-                "{uuid}"
-            } else if parse_sfid(el).is_ok() {
-                "{sfid}"
-            } else if parse_project_reference(el).is_ok() {
-                "{project_reference}"
-            } else if parse_offer_number(el).is_ok() {
-                "{offer_number}"
-            } else if parse_offer_extension_number(el).is_ok() {
-                "{offer_extension_number}"
-            } else {
-                el
-            }
-        })
-        .collect();
-    elements.join("/")
+impl SentryMessage {
+    /// a message for [`crate::LineClassifier`]s outside of this crate.
+    /// Events are grouped by the `fingerprint`.
+    pub fn new(
+        level: Level,
+        fingerprint: Vec<String>,
+        message: impl Into<String>,
+        params: Vec<String>,
+    ) -> Self {
+        Self {
+            level,
+            release: None,
+            tags: HashMap::new(),
+            extra: HashMap::new(),
+            fingerprint,
+            message: message.into(),
+            params,
+        }
+    }
 }
 
-fn generate_dyno_error_message(code: &str, name: &str, logline: &LogLine) -> Option<SentryMessage> {
+/// generate a route-name from the `path` in a router log line.
+pub(crate) fn route_from_router_log(
+    router_log: &RouterLog,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> Option<String> {
+    let path: PathAndQuery = router_log.path?.parse().ok()?;
+    Some(route_from_path(path.path(), templaters, trailing_slash))
+}
+
+pub(crate) fn generate_dyno_error_message(
+    code: &str,
+    name: &str,
+    level: Level,
+    logline: &LogLine,
+) -> Option<SentryMessage> {
     let server_name = logline.source;
     Some(SentryMessage {
+        level,
+        release: None,
+        extra: HashMap::new(),
         tags: HashMap::from_iter(vec![("server_name".into(), server_name.into())]),
         fingerprint: vec![
             format!("heroku-dyno-error-{}", code.to_lowercase()),
             server_name.into(),
         ],
-        message: format!("{} ({}) on {}\n{}", name, code, server_name, logline.text),
+        message: "%s (%s) on %s\n%s".into(),
+        params: vec![
+            name.into(),
+            code.into(),
+            server_name.into(),
+            logline.text.into(),
+        ],
+    })
+}
+
+/// generate a sentry message for an app log line matching one of
+/// the configured `sentry_app_patterns`.
+pub(crate) fn generate_app_error_message(pattern: &str, logline: &LogLine) -> SentryMessage {
+    let server_name = logline.source;
+    SentryMessage {
+        level: Level::Error,
+        release: None,
+        extra: HashMap::new(),
+        tags: HashMap::from_iter(vec![("server_name".into(), server_name.into())]),
+        fingerprint: vec!["app-error".into(), pattern.into()],
+        message: "%s on %s\n%s".into(),
+        params: vec![pattern.into(), server_name.into(), logline.text.into()],
+    }
+}
+
+/// the start of a JSON app log message we group by.
+const MAX_FINGERPRINT_MESSAGE_LENGTH: usize = 200;
+
+/// the message of a JSON app log line for grouping, with identifiers like
+/// numbers or UUIDs replaced by placeholders, and shortened. Otherwise
+/// each order number would get its own sentry issue.
+fn fingerprint_message(message: &str) -> String {
+    let templaters = templaters(1);
+    let mut fingerprint = message
+        .split_whitespace()
+        .map(|word| {
+            let element = word.trim_matches(|c: char| c.is_ascii_punctuation());
+            match templaters
+                .iter()
+                .find_map(|templater| templater.template(element))
+            {
+                Some(placeholder) if !element.is_empty() => word.replace(element, placeholder),
+                _ => word.to_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    truncate_text(&mut fingerprint, MAX_FINGERPRINT_MESSAGE_LENGTH);
+    fingerprint
+}
+
+/// generate a sentry message for a JSON app log line, grouped by its message.
+pub(crate) fn generate_json_app_log_message(
+    json_log: &JsonAppLog,
+    logline: &LogLine,
+) -> SentryMessage {
+    let level = match json_log.level {
+        AppLogLevel::Debug => Level::Debug,
+        AppLogLevel::Info => Level::Info,
+        AppLogLevel::Warning => Level::Warning,
+        AppLogLevel::Error => Level::Error,
+        AppLogLevel::Critical => Level::Fatal,
+    };
+    SentryMessage {
+        level,
+        release: None,
+        extra: HashMap::new(),
+        tags: HashMap::from_iter(vec![("server_name".into(), logline.source.into())]),
+        fingerprint: vec!["app-json".into(), fingerprint_message(&json_log.message)],
+        message: json_log.message.clone(),
+        params: Vec::new(),
+    }
+}
+
+/// a JSON app log line at or above the configured `min_app_log_level`.
+pub(crate) fn forwarded_json_app_log(
+    settings: &DestinationSettings,
+    logline: &LogLine,
+) -> Option<JsonAppLog> {
+    if !settings.sentry_json_logs || !matches!(logline.kind, Kind::App) {
+        return None;
+    }
+    parse_json_app_log(logline.text).filter(|json_log| json_log.level >= settings.min_app_log_level)
+}
+
+/// the first of the configured `sentry_app_patterns` in an app log line.
+pub(crate) fn matching_app_pattern<'a>(
+    settings: &'a DestinationSettings,
+    logline: &LogLine,
+) -> Option<&'a str> {
+    if !matches!(logline.kind, Kind::App) {
+        return None;
+    }
+    settings
+        .sentry_app_patterns
+        .iter()
+        .map(String::as_str)
+        .find(|pattern| logline.text.contains(pattern))
+}
+
+/// generate an info message for a log line that no classifier matched.
+/// Grouped coarsely by destination, process type and the first word.
+pub(crate) fn generate_unclassified_message(
+    destination_name: &str,
+    logline: &LogLine,
+) -> SentryMessage {
+    let kind = match logline.kind {
+        Kind::Heroku => "heroku",
+        Kind::App => "app",
+    };
+    let process_type = logline
+        .source
+        .split_once('.')
+        .map_or(logline.source, |(process_type, _)| process_type);
+    let first_word = logline.text.split_whitespace().next().unwrap_or_default();
+
+    SentryMessage {
+        level: Level::Info,
+        release: None,
+        extra: HashMap::new(),
+        tags: HashMap::from_iter(vec![
+            ("destination".into(), destination_name.into()),
+            ("server_name".into(), logline.source.into()),
+        ]),
+        fingerprint: vec![
+            "unclassified".into(),
+            destination_name.into(),
+            kind.into(),
+            process_type.into(),
+            first_word.into(),
+        ],
+        message: logline.text.into(),
+        params: Vec::new(),
+    }
+}
+
+/// generate the route-name and the common sentry tags for router log lines.
+fn generate_router_tags(
+    router_log: &RouterLog,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> Option<(String, HashMap<String, String>)> {
+    let mut tags: HashMap<String, String> = HashMap::new();
+
+    let full_url = Uri::builder()
+        .scheme("https")
+        .authority(router_log.host?)
+        .path_and_query(router_log.path?)
+        .build()
+        .ok()?;
+
+    let route_name = route_from_path(full_url.path(), templaters, trailing_slash);
+
+    tags.insert("transaction".into(), route_name.clone());
+    tags.insert("url".into(), full_url.to_string());
+
+    if let Some(request_id) = router_log.request_id {
+        tags.insert("request_id".into(), request_id.to_string());
+    }
+
+    if let Some(dyno) = router_log.dyno {
+        tags.insert("server_name".into(), dyno.to_string());
+    }
+
+    Some((route_name, tags))
+}
+
+pub(crate) fn generate_request_timeout_message(
+    logline: &LogLine,
+    router_log: &RouterLog,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> Option<SentryMessage> {
+    let (route_name, tags) = generate_router_tags(router_log, templaters, trailing_slash)?;
+
+    Some(SentryMessage {
+        level: Level::Error,
+        release: None,
+        extra: HashMap::new(),
+        tags,
+        fingerprint: vec!["heroku-router-request-timeout".into(), route_name.clone()],
+        message: "request timeout on %s\n%s".into(),
+        params: vec![route_name, logline.text.into()],
+    })
+}
+
+pub(crate) fn generate_router_error_message(
+    code: &str,
+    logline: &LogLine,
+    router_log: &RouterLog,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> Option<SentryMessage> {
+    let (route_name, tags) = generate_router_tags(router_log, templaters, trailing_slash)?;
+    let name = router_log
+        .desc
+        .or_else(|| heroku_error_name(code))
+        .unwrap_or("router error");
+
+    Some(SentryMessage {
+        level: Level::Error,
+        release: None,
+        extra: HashMap::new(),
+        tags,
+        fingerprint: vec![
+            format!("heroku-router-error-{}", code.to_lowercase()),
+            route_name.clone(),
+        ],
+        message: "%s (%s) on %s\n%s".into(),
+        params: vec![name.into(), code.into(), route_name, logline.text.into()],
     })
 }
 
-fn generate_request_timeout_message(logline: &LogLine, items: &LogMap) -> Option<SentryMessage> {
-    let mut tags: HashMap<String, String> = HashMap::new();
+pub(crate) fn generate_router_warning_message(
+    logline: &LogLine,
+    router_log: &RouterLog,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> Option<SentryMessage> {
+    let (route_name, tags) = generate_router_tags(router_log, templaters, trailing_slash)?;
+    let code = router_log.code.unwrap_or("unknown");
+
+    Some(SentryMessage {
+        level: Level::Warning,
+        release: None,
+        extra: HashMap::new(),
+        tags,
+        fingerprint: vec![
+            "heroku-router-warning".into(),
+            code.to_lowercase(),
+            route_name.clone(),
+        ],
+        message: "router warning (%s) on %s\n%s".into(),
+        params: vec![code.into(), route_name, logline.text.into()],
+    })
+}
+
+pub(crate) fn generate_insecure_request_message(
+    logline: &LogLine,
+    router_log: &RouterLog,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> Option<SentryMessage> {
+    let (route_name, tags) = generate_router_tags(router_log, templaters, trailing_slash)?;
+
+    Some(SentryMessage {
+        level: Level::Warning,
+        release: None,
+        extra: HashMap::new(),
+        tags,
+        fingerprint: vec!["heroku-router-insecure-request".into(), route_name.clone()],
+        message: "insecure request over http on %s\n%s".into(),
+        params: vec![route_name, logline.text.into()],
+    })
+}
+
+/// truncate the text to `max_length` characters with an ellipsis.
+/// Returns `true` when it was too long.
+fn truncate_text(text: &mut String, max_length: usize) -> bool {
+    let Some((index, _)) = text.char_indices().nth(max_length) else {
+        return false;
+    };
+    text.truncate(index);
+    text.push('…');
+    true
+}
+
+/// truncate the message params, like the log line, to `max_length`
+/// characters with an ellipsis, and tag the message as `truncated`.
+/// Messages without params, like JSON app logs, are the log text
+/// themselves and are truncated too, like the fingerprint built from them.
+fn truncate_message(message: &mut SentryMessage, max_length: usize) {
+    let mut truncated = false;
+    for param in &mut message.params {
+        truncated |= truncate_text(param, max_length);
+    }
+    if message.params.is_empty() {
+        truncated |= truncate_text(&mut message.message, max_length);
+    }
+    for part in &mut message.fingerprint {
+        truncate_text(part, max_length);
+    }
+    if truncated {
+        message.tags.insert("truncated".into(), "true".into());
+    }
+}
+
+/// read the logfmt `key` from the log line, if present.
+/// Used for the sentry environment and release.
+fn value_from_log(key: &str, logline: &LogLine) -> Option<String> {
+    if !looks_like_logfmt(logline.text) {
+        return None;
+    }
+    let (_, pairs) = parse_key_value_pairs(logline.text).ok()?;
+    pairs.get(key).map(|value| value.to_string())
+}
+
+/// capture the message in sentry.
+///
+/// Returns `false` when the client didn't accept the message,
+/// for example because it's disabled or already closed.
+#[instrument(fields(dsn=?sentry_client.dsn()), skip(sentry_client))]
+fn send_to_sentry(
+    sentry_client: Arc<Client>,
+    message: &SentryMessage,
+    environment: Option<&str>,
+) -> bool {
+    debug!(?message, "reporting to sentry");
+
+    // uses an empty & new scope instead of the
+    // standard scope which would include details of
+    // this specific service.
+    let mut scope = Scope::default();
+    scope.set_level(Some(message.level));
+    for (key, value) in &message.tags {
+        scope.set_tag(key, value);
+    }
+    for (key, value) in &message.extra {
+        scope.set_extra(key, value.as_str().into());
+    }
+
+    // the fingerprint is used for grouping the messages in sentry.
+    let fingerprint: Vec<_> = message.fingerprint.iter().map(String::as_str).collect();
+    scope.set_fingerprint(Some(&fingerprint));
+
+    // overrides the environment of the client. Events without environment
+    // get the one from the client options.
+    if let Some(environment) = environment {
+        let environment = environment.to_owned();
+        scope.add_event_processor(move |mut event| {
+            event.environment = Some(environment.clone().into());
+            Some(event)
+        });
+    }
+
+    if let Some(ref release) = message.release {
+        let release = release.clone();
+        scope.add_event_processor(move |mut event| {
+            event.release = Some(release.clone().into());
+            Some(event)
+        });
+    }
+
+    let hub = Hub::new(Some(sentry_client), Arc::new(scope));
+    let uuid = hub.capture_event(Event {
+        level: message.level,
+        logentry: Some(LogEntry {
+            message: message.message.clone(),
+            params: message
+                .params
+                .iter()
+                .map(|param| param.as_str().into())
+                .collect(),
+        }),
+        ..Default::default()
+    });
+    info!(?uuid, last_event_id = ?hub.last_event_id(), "captured message");
+    !uuid.is_nil()
+}
+
+/// send the message to the sentry client picked for it.
+///
+/// Envelopes the transport can't send right away are kept and retried
+/// there, see `sentry_transport`. A client that doesn't accept the message
+/// at all, for example because it's disabled, is only counted.
+fn capture(
+    destination: &Destination,
+    sentry_client: Arc<Client>,
+    message: &SentryMessage,
+    environment: Option<&str>,
+) -> bool {
+    if send_to_sentry(sentry_client, message, environment) {
+        return true;
+    }
+    debug!(destination.name, "sentry client didn't accept message");
+    destination.counters.increment("sentry_capture_failures");
+    false
+}
+
+/// consecutive identical lines in a batch, with the sentry reports
+/// of the first line, see `collapse_repeats`.
+struct Repeats {
+    source: String,
+    text: String,
+    timestamp: DateTime<FixedOffset>,
+    count: usize,
+    reports: Vec<(SentryMessage, Option<String>, Arc<Client>)>,
+}
+
+impl Repeats {
+    fn new(log: &LogLine) -> Self {
+        Self {
+            source: log.source.to_owned(),
+            text: log.text.to_owned(),
+            timestamp: log.timestamp,
+            count: 1,
+            reports: Vec::new(),
+        }
+    }
+}
+
+/// what we did with a single batch of logs.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct BatchSummary {
+    pub(crate) lines: usize,
+    pub(crate) sentry_events: usize,
+}
+
+/// process a batch of log lines. `environment` overrides the sentry
+/// environment of the destination for the batch, `tags` are added to its
+/// sentry events like the `static_tags`.
+#[instrument(fields(dsn=?destination.sentry_client.dsn()), skip(destination))]
+pub(crate) fn process_logs(
+    destination: Arc<Destination>,
+    input: &str,
+    environment: Option<&str>,
+    tags: &[(&str, &str)],
+) -> Result<BatchSummary> {
+    let mut heroku_lines: usize = 0;
+    let mut app_lines: usize = 0;
+    let mut drain_lags_ms: Vec<f64> = Vec::new();
+    // per line with sentry events or measurements, see `event_latency_ms`.
+    let mut event_latencies_ms: Vec<f64> = Vec::new();
+    let sentry_events = Cell::new(0);
+    // see `sentry.distinct_fingerprints`, including sampled out events.
+    let fingerprints: RefCell<HashSet<Vec<String>>> = RefCell::new(HashSet::new());
+    // the current run of identical lines, see `collapse_repeats`.
+    let repeats: RefCell<Option<Repeats>> = RefCell::new(None);
+
+    let send = |mut message: SentryMessage,
+                environment: Option<String>,
+                sentry_client: Arc<Client>,
+                count: usize| {
+        let settings = &destination.settings;
+        if count > 1 {
+            message.tags.insert("count".into(), count.to_string());
+        }
+        for (key, value) in tags {
+            message
+                .tags
+                .entry((*key).to_owned())
+                .or_insert_with(|| (*value).to_owned());
+        }
+        for (key, value) in &destination.settings.static_tags {
+            message
+                .tags
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        truncate_message(&mut message, settings.sentry_max_message_length);
+        for key in &destination.settings.sentry_extra_tags {
+            if let Some(value) = message.tags.remove(key) {
+                message.extra.insert(key.clone(), value);
+            }
+        }
+
+        if !fingerprints.borrow().contains(&message.fingerprint) {
+            fingerprints
+                .borrow_mut()
+                .insert(message.fingerprint.clone());
+        }
+
+        if destination.sentry_sampler.sample(
+            &message.fingerprint,
+            destination.settings.sentry_sample_rate,
+        ) {
+            if capture(
+                &destination,
+                sentry_client,
+                &message,
+                environment.as_deref(),
+            ) {
+                sentry_events.set(sentry_events.get() + 1);
+            }
+        } else {
+            destination.counters.increment("sentry_events_sampled_out");
+        }
+    };
+
+    let report_with = |mut message: SentryMessage, log: &LogLine, sentry_client: Arc<Client>| {
+        let settings = &destination.settings;
+        let environment = settings
+            .sentry_environment_key
+            .as_deref()
+            .and_then(|key| value_from_log(key, log))
+            .or_else(|| environment.map(ToOwned::to_owned))
+            .or_else(|| destination.sentry_environment.clone());
+        message.release = settings
+            .sentry_release_key
+            .as_deref()
+            .and_then(|key| value_from_log(key, log))
+            .or_else(|| settings.sentry_release.clone());
+
+        // the count is only known when the run of identical lines ends.
+        match repeats.borrow_mut().as_mut() {
+            Some(run) => run.reports.push((message, environment, sentry_client)),
+            None => send(message, environment, sentry_client, 1),
+        }
+    };
+
+    let report = |message: SentryMessage, log: &LogLine, classifier: &str| {
+        let sentry_client = destination.route_sentry_client(log, classifier).clone();
+        report_with(message, log, sentry_client);
+    };
+
+    let finish_repeats = || {
+        let Some(run) = repeats.take() else {
+            return;
+        };
+        if run.count > 1 {
+            if let Some(measurement) =
+                generate_librato_repeated_lines_metric(&run.timestamp, &run.source, run.count)
+            {
+                destination.add_measurement(measurement);
+            }
+        }
+        for (message, environment, sentry_client) in run.reports {
+            send(message, environment, sentry_client, run.count);
+        }
+    };
+
+    let mut batch = BatchState::default();
+
+    // the rest of the truncated last frame of the previous batch
+    // is at the start of this one.
+    let completed;
+    let input = match destination.take_truncated_frame() {
+        Some(frame) => {
+            completed = frame + input.trim_start();
+            let first_line = completed.lines().next().unwrap_or_default();
+            if parse_log_line(first_line.trim()).is_ok() {
+                completed.as_str()
+            } else {
+                warn!(
+                    ?first_line,
+                    "could not complete truncated frame, dropping it"
+                );
+                destination.counters.increment("truncated_frames_dropped");
+                input
+            }
+        }
+        None => input,
+    };
+
+    let started = Instant::now();
+    let mut lines = input.lines();
+    let mut line_number: usize = 0;
+    while let Some(raw_line) = lines.next() {
+        debug!("handling log line: {}", raw_line);
+
+        // a single huge batch shouldn't block the worker for too long.
+        if started.elapsed() > destination.batch_timeout {
+            warn!(
+                destination = destination.name,
+                line_number, "batch timeout, skipping the rest of the batch"
+            );
+            destination.counters.increment("batch_timeout_total");
+            break;
+        }
+        line_number += 1;
+
+        let line = raw_line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parse_started = Instant::now();
+        let (_, log) = match parse_log_line(line) {
+            Ok(parsed) => parsed,
+            // logplex sometimes cuts off the last frame of a batch,
+            // the next batch starts with the rest of it.
+            Err(_)
+                if lines.clone().all(|rest| rest.trim().is_empty()) && is_truncated_frame(line) =>
+            {
+                debug!(?line, "buffering truncated last frame");
+                destination.buffer_truncated_frame(raw_line.trim_start());
+                continue;
+            }
+            Err(err) => {
+                finish_repeats();
+                return Err(err.to_owned()).context("could not parse log line");
+            }
+        };
+
+        let forward_client = destination
+            .forward_client
+            .as_ref()
+            .filter(|_| destination.settings.forwards_source(log.source));
+        if let Some(forward_client) = forward_client {
+            forward_client.add_line(line);
+        }
+
+        // crash loops repeat the same line, we report it to sentry once.
+        // the lines are still classified for their metrics.
+        let is_repeat = repeats
+            .borrow_mut()
+            .as_mut()
+            .filter(|run| run.source == log.source && run.text == log.text)
+            .map(|run| run.count += 1)
+            .is_some();
+        if !is_repeat {
+            finish_repeats();
+            if destination.settings.collapse_repeats {
+                *repeats.borrow_mut() = Some(Repeats::new(&log));
+            }
+        }
+
+        match log.kind {
+            Kind::Heroku => heroku_lines += 1,
+            Kind::App => app_lines += 1,
+        }
+
+        drain_lags_ms.push(
+            Utc::now()
+                .signed_duration_since(log.timestamp)
+                .num_milliseconds() as f64,
+        );
+
+        let parsed = ParsedLine::new(log);
+        for classifier in &destination.classifiers {
+            if destination
+                .settings
+                .disabled_classifiers
+                .iter()
+                .any(|name| name == classifier.name())
+            {
+                continue;
+            }
+            let actions = match classifier.classify(&destination, &parsed, &mut batch) {
+                Ok(Some(actions)) => actions,
+                Ok(None) => continue,
+                Err(err) => {
+                    finish_repeats();
+                    return Err(err);
+                }
+            };
+            let has_events = !actions.is_empty();
+            for action in actions {
+                match action {
+                    Action::Report(_) | Action::ReportUnclassified(_) if is_repeat => {}
+                    Action::Report(msg) => report(msg, &parsed.log, classifier.name()),
+                    Action::ReportUnclassified(msg) => {
+                        if let Some(ref client) = destination.unclassified_sentry_client {
+                            report_with(msg, &parsed.log, client.clone());
+                        }
+                    }
+                    Action::Measure(measurement) => destination.add_measurement(measurement),
+                }
+            }
+            if has_events {
+                event_latencies_ms.push(parse_started.elapsed().as_secs_f64() * 1000.0);
+            }
+            break;
+        }
+    }
+    finish_repeats();
+
+    if heroku_lines + app_lines > 0 {
+        for measurement in generate_librato_line_count_metrics(
+            &Utc::now().fixed_offset(),
+            &[(Kind::Heroku, heroku_lines), (Kind::App, app_lines)],
+        ) {
+            destination.add_measurement(measurement);
+        }
+    }
+
+    if let Some(lag_ms) = percentile(&mut drain_lags_ms, 95.0) {
+        if lag_ms > destination.settings.drain_lag_warning.as_millis() as f64
+            && destination.drain_lag_log_throttle.allow()
+        {
+            warn!(
+                destination = destination.name,
+                lag_ms, "high drain lag, logplex or we are too slow"
+            );
+        }
+        if let Some(measurement) =
+            generate_librato_drain_lag_metric(&Utc::now().fixed_offset(), lag_ms)
+        {
+            destination.add_measurement(measurement);
+        }
+    }
+
+    let fingerprints = fingerprints.borrow().len();
+    if fingerprints > 0 {
+        if let Some(measurement) =
+            generate_librato_distinct_fingerprints_metric(&Utc::now().fixed_offset(), fingerprints)
+        {
+            destination.add_measurement(measurement);
+        }
+    }
+
+    for measurement in
+        generate_librato_event_latency_metrics(&Utc::now().fixed_offset(), &mut event_latencies_ms)
+    {
+        destination.add_measurement(measurement);
+    }
+
+    Ok(BatchSummary {
+        lines: heroku_lines + app_lines,
+        sentry_events: sentry_events.get(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        classifiers::{BatchState, LineClassifier, ParsedLine},
+        config::{Config, ConfigBuilder},
+        routes::DEFAULT_TEMPLATERS,
+        test_utils::initialize_tracing,
+    };
+    use test_case::test_case;
+
+    #[test]
+    fn test_process_log() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com \
+            request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
+            fwd=\"204.204.204.204\" dyno=web.1 connect=0ms service=30000ms \
+            status=503 bytes=0 protocol=https\
+            ";
+
+        let events =
+            config.with_captured_sentry_events_sync("logplex_token", |sentry_client, _cfg| {
+                process_logs(sentry_client, input, None, &[]).expect("error processing logs");
+            });
+
+        assert_eq!(events.len(), 1);
+        assert!(events[0].message.is_none());
+        assert_eq!(
+            events[0].logentry,
+            Some(LogEntry {
+                message: "request timeout on %s\n%s".into(),
+                params: vec![
+                    "/".into(),
+                    "at=error code=H12 desc=\"Request timeout\" \
+                     method=GET path=/ host=myapp.herokuapp.com \
+                     request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
+                     fwd=\"204.204.204.204\" dyno=web.1 connect=0ms \
+                     service=30000ms status=503 bytes=0 protocol=https"
+                        .into(),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_process_log_summary() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            Started GET /some/path
+            ";
+
+        config.with_captured_sentry_events_sync("logplex_token", |destination, _cfg| {
+            assert_eq!(
+                process_logs(destination, input, None, &[]).expect("error processing logs"),
+                BatchSummary {
+                    lines: 2,
+                    sentry_events: 1
+                }
+            );
+        });
+    }
+
+    #[test_case(false, 0; "disabled")]
+    #[test_case(true, 1; "enabled")]
+    fn test_router_warning_process_log(sentry_router_warnings: bool, expected_events: usize) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=warning code=H81 desc=\"Blank app\" method=GET \
+            path=/some/1234/ host=myapp.herokuapp.com \
+            request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
+            fwd=\"204.204.204.204\" dyno=web.1 connect=0ms service=30ms \
+            status=200 bytes=0 protocol=https\
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            DestinationSettings {
+                sentry_router_warnings,
+                ..Default::default()
+            },
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), expected_events);
+        if let Some(event) = events.first() {
+            assert_eq!(event.level, Level::Warning);
+            let logentry = event.logentry.as_ref().unwrap();
+            assert_eq!(logentry.message, "router warning (%s) on %s\n%s");
+            assert_eq!(logentry.params[..2], ["H81", "/some/{number}/"]);
+        }
+    }
+
+    #[test_case("", 0, 0; "disabled")]
+    #[test_case("insecure_request_metrics=true", 1, 0; "metric")]
+    #[test_case("insecure_request_metrics=true sentry_insecure_requests=true", 1, 1; "metric and sentry")]
+    fn test_insecure_request_process_log(
+        settings: &str,
+        expected_measurements: usize,
+        expected_events: usize,
+    ) {
+        let _ = initialize_tracing();
+
+        // a current timestamp, so the measurements aren't dropped as skewed.
+        let input = format!(
+            "111 <158>1 {now} host heroku router - \
+            at=info method=GET path=/some/1234/ host=myapp.herokuapp.com \
+            dyno=web.1 connect=0ms service=30ms status=200 protocol=http
+            111 <158>1 {now} host heroku router - \
+            at=info method=GET path=/some/1234/ host=myapp.herokuapp.com \
+            dyno=web.1 connect=0ms service=30ms status=200 protocol=https",
+            now = Utc::now().to_rfc3339(),
+        );
+
+        let events = Config::default().with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+        assert_eq!(events.len(), expected_events);
+        if let Some(event) = events.first() {
+            assert_eq!(event.level, Level::Warning);
+            let logentry = event.logentry.as_ref().unwrap();
+            assert_eq!(logentry.message, "insecure request over http on %s\n%s");
+            assert_eq!(logentry.params[0], "/some/{number}/");
+        }
+
+        let measurements = Config::default().with_captured_measurements_sync(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+        let insecure: Vec<_> = measurements
+            .iter()
+            .filter(|m| m.name == "router.insecure_requests")
+            .collect();
+        assert_eq!(insecure.len(), expected_measurements);
+        if let Some(measurement) = insecure.first() {
+            assert_eq!(measurement.source, "myapp.herokuapp.com");
+            assert_eq!(measurement.value, 1.0);
+        }
+    }
+
+    #[test_case("", 0; "default")]
+    #[test_case("router_error_markers=error,crit", 1; "custom marker")]
+    fn test_router_error_markers_process_log(settings: &str, expected_events: usize) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=crit code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), expected_events);
+    }
+
+    #[test_case("", 0; "not listed")]
+    #[test_case("sentry_router_error_codes=H15,H28", 2; "listed")]
+    fn test_router_error_codes_process_log(settings: &str, expected_events: usize) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H15 desc=\"Idle connection\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H28 desc=\"Client Connection Idle\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=499 bytes=0
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H13 desc=\"Connection closed without response\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), expected_events);
+        if expected_events > 0 {
+            let logentry = events[0].logentry.as_ref().unwrap();
+            assert_eq!(logentry.message, "%s (%s) on %s\n%s");
+            assert_eq!(logentry.params[..3], ["Idle connection", "H15", "/"]);
+            assert_eq!(events[1].fingerprint, vec!["heroku-router-error-h28", "/"]);
+        }
+    }
+
+    #[test]
+    fn test_boot_duration_process_log() {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            Some(crate::librato::Client::new(
+                "username",
+                "token",
+                None,
+                "invalid_endpoint",
+            )),
+        ));
+
+        let now = Utc::now();
+        let started = now - chrono::TimeDelta::seconds(5);
+        let input = format!(
+            "100 <134>1 {} host heroku web.1 - Starting process with command `bundle exec puma`
+            100 <134>1 {} host heroku web.2 - State changed from starting to up
+            100 <134>1 {} host heroku web.1 - State changed from starting to up
+            100 <134>1 {} host heroku web.3 - Stopping all processes with SIGTERM",
+            started.to_rfc3339(),
+            now.to_rfc3339(),
+            now.to_rfc3339(),
+            now.to_rfc3339(),
+        );
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        // web.2 has no start in this batch, only web.1 has a boot duration.
+        let measurements = destination
+            .librato_client
+            .as_ref()
+            .unwrap()
+            .queued_measurements();
+        let boot_durations: Vec<_> = measurements
+            .iter()
+            .filter(|measurement| measurement.name == "dyno.boot_duration_ms")
+            .collect();
+        let [boot_duration] = &boot_durations[..] else {
+            panic!("expected a single boot duration, got {:?}", boot_durations);
+        };
+        assert_eq!(boot_duration.source, "web.1");
+        assert_eq!(boot_duration.value, 5000.0);
+
+        let names: Vec<&str> = measurements
+            .iter()
+            .map(|measurement| measurement.name.as_str())
+            .collect();
+        assert!(names.contains(&"dyno.lifecycle.starting"));
+        assert!(names.contains(&"dyno.lifecycle.stopping"));
+    }
+
+    #[test_case("", 3; "all sources")]
+    #[test_case("forward_sources=web", 2; "web dynos")]
+    #[test_case("forward_sources=router,worker", 1; "other sources")]
+    #[test_case("forward_sources=we", 0; "source prefix only")]
+    #[tokio::test]
+    async fn test_forward_process_log(settings: &str, expected: usize) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(
+            Destination::new(
+                "test",
+                Arc::new(Client::from(sentry::ClientOptions::default())),
+                None,
+            )
+            .with_forward_client(Some(crate::forward::Client::new(
+                "invalid_endpoint",
+                "token",
+                None,
+            )))
+            .with_settings(settings.parse().unwrap()),
+        );
+
+        let input = "
+            83 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - first line
+            84 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.2 - second line
+            85 <190>1 2022-12-05T08:59:21.850424+00:00 host heroku router - at=info status=200
+            ";
+
+        process_logs(destination.clone(), input, None, &[]).expect("error processing logs");
+
+        assert_eq!(
+            destination.forward_client.as_ref().unwrap().queue_len(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_batch_timeout() {
+        let _ = initialize_tracing();
+
+        let mut destination = Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            None,
+        );
+        destination.batch_timeout = std::time::Duration::ZERO;
+        let destination = Arc::new(destination);
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let summary =
+            process_logs(destination.clone(), input, None, &[]).expect("error processing logs");
+
+        assert_eq!(summary.lines, 0);
+        assert_eq!(destination.counters.get("batch_timeout_total"), 1);
+    }
+
+    #[test]
+    fn test_static_tags_process_log() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            "static_tags=team:payments,server_name:ignored"
+                .parse()
+                .unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].tags.get("team").unwrap(), "payments");
+        // tags of the message itself win
+        assert_eq!(events[0].tags.get("server_name").unwrap(), "web.1");
+    }
+
+    #[test]
+    fn test_sentry_max_message_length_process_log() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            "sentry_max_message_length=20".parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        let logentry = events[0].logentry.as_ref().unwrap();
+        assert_eq!(logentry.params[0], "/");
+        assert_eq!(logentry.params[1], "at=error code=H12 de…");
+        assert_eq!(events[0].tags.get("truncated").unwrap(), "true");
+    }
+
+    #[test]
+    fn test_sentry_max_message_length_json_process_log() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = format!(
+            r#"111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - {{"level": "error", "message": "{}"}}"#,
+            "payment failed ".repeat(100)
+        );
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            "sentry_json_logs=true sentry_max_message_length=20"
+                .parse()
+                .unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        let logentry = events[0].logentry.as_ref().unwrap();
+        assert_eq!(logentry.message, "payment failed payme…");
+        assert_eq!(
+            events[0].fingerprint,
+            vec!["app-json", "payment failed payme…"]
+        );
+        assert_eq!(events[0].tags.get("truncated").unwrap(), "true");
+    }
+
+    #[test]
+    fn test_sentry_extra_tags_process_log() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com request_id=abc dyno=web.1 status=503 bytes=0
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            "sentry_extra_tags=request_id,url".parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].tags.contains_key("request_id"));
+        assert!(!events[0].tags.contains_key("url"));
+        assert_eq!(events[0].extra.get("request_id").unwrap(), "abc");
+        assert_eq!(
+            events[0].extra.get("url").unwrap(),
+            "https://myapp.herokuapp.com/"
+        );
+        assert_eq!(events[0].tags.get("server_name").unwrap(), "web.1");
+    }
+
+    #[test_case(" env=staging", Some("staging"); "from log line")]
+    #[test_case("", None; "fallback")]
+    fn test_sentry_environment_from_log(extra: &str, expected: Option<&str>) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = format!(
+            "111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0{}",
+            extra
+        );
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            "sentry_environment_key=env".parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].environment.as_deref(), expected);
+    }
+
+    #[test_case(" env=production", None, "production"; "from log line")]
+    #[test_case("", None, "staging"; "from mapping")]
+    #[test_case("", Some("review"), "review"; "from request")]
+    #[test_case(" env=production", Some("review"), "production"; "log line before request")]
+    fn test_sentry_environment_per_event(extra: &str, header: Option<&str>, expected: &str) {
+        let _ = initialize_tracing();
+        let test_transport = Arc::new(sentry::test::TestTransport::new());
+        let client = Arc::new(Client::from((
+            "https://public@example.com/1".to_owned(),
+            sentry::ClientOptions {
+                transport: Some(test_transport.clone()),
+                ..Default::default()
+            },
+        )));
+
+        // two mappings sharing the sentry client
+        let staging = Arc::new(
+            Destination::new("staging", client.clone(), None)
+                .with_sentry_environment("staging")
+                .with_settings("sentry_environment_key=env".parse().unwrap()),
+        );
+        let other =
+            Arc::new(Destination::new("other", client, None).with_sentry_environment("other"));
+
+        let input = format!(
+            "111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0{}",
+            extra
+        );
+        process_logs(staging, &input, header, &[]).expect("error processing logs");
+        process_logs(other, &input, None, &[]).expect("error processing logs");
+
+        let environments: Vec<_> = test_transport
+            .fetch_and_clear_envelopes()
+            .iter()
+            .filter_map(|envelope| envelope.event().cloned())
+            .map(|event| event.environment.map(|env| env.to_string()))
+            .collect();
+        assert_eq!(
+            environments,
+            vec![Some(expected.to_owned()), Some("other".to_owned())]
+        );
+    }
+
+    #[test_case("", "", None; "not configured")]
+    #[test_case("sentry_release=v12", "", Some("v12"); "from config")]
+    #[test_case("sentry_release=v12 sentry_release_key=release", " release=v13", Some("v13"); "from log line")]
+    #[test_case("sentry_release=v12 sentry_release_key=release", "", Some("v12"); "fallback")]
+    fn test_sentry_release(settings: &str, extra: &str, expected: Option<&str>) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = format!(
+            "111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0{}",
+            extra
+        );
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].release.as_deref(), expected);
+    }
+
+    #[test_case("", 0; "not configured")]
+    #[test_case("sentry_app_patterns=\"Traceback (most recent call last):,CRITICAL\"", 2; "configured")]
+    #[test_case(
+        "sentry_app_patterns=CRITICAL disabled_classifiers=app_pattern", 0; "classifier disabled"
+    )]
+    fn test_app_patterns_process_log(settings: &str, expected_events: usize) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            Traceback (most recent call last):
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app worker.1 - \
+            CRITICAL something went wrong
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            INFO all good
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), expected_events);
+        if expected_events > 0 {
+            assert_eq!(
+                events[0].fingerprint,
+                vec!["app-error", "Traceback (most recent call last):"]
+            );
+            assert_eq!(
+                events[1].logentry,
+                Some(LogEntry {
+                    message: "%s on %s\n%s".into(),
+                    params: vec![
+                        "CRITICAL".into(),
+                        "worker.1".into(),
+                        "CRITICAL something went wrong".into(),
+                    ],
+                })
+            );
+        }
+    }
+
+    #[test_case("", 3, None; "disabled")]
+    #[test_case("collapse_repeats=true", 2, Some("2"); "enabled")]
+    fn test_collapse_repeats_process_log(
+        settings: &str,
+        expected_events: usize,
+        expected_count: Option<&str>,
+    ) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <134>1 2023-04-29T23:11:12.604871+00:00 host heroku web.1 - Error R14 (Memory quota exceeded)
+            111 <134>1 2023-04-29T23:11:13.604871+00:00 host heroku web.1 - Error R14 (Memory quota exceeded)
+            111 <134>1 2023-04-29T23:11:14.604871+00:00 host heroku web.2 - Error R14 (Memory quota exceeded)
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), expected_events);
+        assert_eq!(
+            events[0].tags.get("count").map(String::as_str),
+            expected_count
+        );
+        assert!(!events.last().unwrap().tags.contains_key("count"));
+    }
+
+    #[test]
+    fn test_collapse_repeats_keeps_metrics_process_log() {
+        let _ = initialize_tracing();
+        let settings = "collapse_repeats=true insecure_request_metrics=true \
+                        sentry_insecure_requests=true";
+
+        let line = format!(
+            "111 <158>1 {} host heroku router - \
+            at=info method=GET path=/some/1234/ host=myapp.herokuapp.com \
+            dyno=web.1 connect=0ms service=30ms status=200 protocol=http",
+            Utc::now().to_rfc3339(),
+        );
+        let input = [line.as_str(); 3].join("\n");
+
+        let events = Config::default().with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                assert_eq!(
+                    process_logs(destination, &input, None, &[]).expect("error processing logs"),
+                    BatchSummary {
+                        lines: 3,
+                        sentry_events: 1
+                    }
+                );
+            },
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].tags.get("count").map(String::as_str), Some("3"));
+
+        let measurements = Config::default().with_captured_measurements_sync(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+        let insecure = measurements
+            .iter()
+            .filter(|m| m.name == "router.insecure_requests")
+            .count();
+        assert_eq!(insecure, 3);
+        let repeated: Vec<_> = measurements
+            .iter()
+            .filter(|m| m.name == "repeated_lines")
+            .collect();
+        assert_eq!(repeated.len(), 1);
+        assert_eq!(repeated[0].source, "router");
+        assert_eq!(repeated[0].value, 3.0);
+        assert!(measurements.iter().any(|m| m.name == "drain_lag_ms"));
+    }
+
+    #[test_case("database is gone", "database is gone"; "unchanged")]
+    #[test_case("payment 123 failed.", "payment {number} failed."; "number")]
+    #[test_case(
+        "user 7a1f1c9e-3c2b-4e1a-9d5e-0b8c2f4e6a1d not found",
+        "user {uuid} not found";
+        "uuid"
+    )]
+    #[test_case("order (4711) failed", "order ({number}) failed"; "punctuation")]
+    fn test_fingerprint_message(message: &str, expected: &str) {
+        assert_eq!(fingerprint_message(message), expected);
+    }
+
+    #[test]
+    fn test_fingerprint_message_is_truncated() {
+        let fingerprint = fingerprint_message(&"a".repeat(1000));
+        assert_eq!(
+            fingerprint.chars().count(),
+            MAX_FINGERPRINT_MESSAGE_LENGTH + 1
+        );
+        assert!(fingerprint.ends_with('…'));
+    }
+
+    #[test_case("", 0; "disabled")]
+    #[test_case("sentry_json_logs=true", 2; "error and above")]
+    #[test_case("sentry_json_logs=true min_app_log_level=warning", 3; "warning and above")]
+    #[test_case("sentry_json_logs=true min_app_log_level=critical", 1; "only critical")]
+    fn test_json_app_logs_process_log(settings: &str, expected_events: usize) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = r#"
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - {"level": "critical", "message": "database is gone"}
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - {"level": "error", "message": "payment failed"}
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - {"level": "warning", "message": "slow request"}
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - {"level": "info", "message": "all good"}
+            "#;
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(events.len(), expected_events);
+        if expected_events > 0 {
+            assert_eq!(events[0].level, Level::Fatal);
+            assert_eq!(events[0].fingerprint, vec!["app-json", "database is gone"]);
+            assert_eq!(
+                events[0]
+                    .logentry
+                    .as_ref()
+                    .map(|logentry| &logentry.message[..]),
+                Some("database is gone")
+            );
+        }
+    }
+
+    #[test_case("", 3; "send all")]
+    #[test_case("sentry_sample_rate=0.5", 2; "half")]
+    #[test_case("sentry_sample_rate=0.0", 1; "only first")]
+    fn test_sentry_sample_rate_process_log(settings: &str, expected_events: usize) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let line = "111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0";
+        let input = [line, line, line].join("\n");
 
-    let path = items.get("path")?;
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            settings.parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination.clone(), &input, None, &[])
+                    .expect("error processing logs");
+                assert_eq!(
+                    destination.counters.get("sentry_events_sampled_out"),
+                    (3 - expected_events) as u64
+                );
+            },
+        );
 
-    let full_url = Uri::builder()
-        .scheme("https")
-        .authority(*items.get("host")?)
-        .path_and_query(*path)
-        .build()
-        .ok()?;
+        assert_eq!(events.len(), expected_events);
+    }
 
-    let route_name = route_from_path(full_url.path());
+    #[test]
+    fn test_rejected_sentry_messages_are_counted() {
+        let _ = initialize_tracing();
 
-    tags.insert("transaction".into(), route_name.clone());
-    tags.insert("url".into(), full_url.to_string());
+        // a client without DSN is disabled and doesn't accept any events.
+        let destination = Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            None,
+        );
 
-    if let Some(request_id) = items.get("request_id") {
-        tags.insert("request_id".into(), request_id.to_string());
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let destination = Arc::new(destination);
+        let summary =
+            process_logs(destination.clone(), input, None, &[]).expect("error processing logs");
+        assert_eq!(summary.sentry_events, 0);
+        assert_eq!(destination.counters.get("sentry_capture_failures"), 1);
+
+        // rejected messages aren't retried with the next batch.
+        process_logs(destination.clone(), input, None, &[]).expect("error processing logs");
+        assert_eq!(destination.counters.get("sentry_capture_failures"), 2);
     }
 
-    if let Some(dyno) = items.get("dyno") {
-        tags.insert("server_name".into(), dyno.to_string());
+    #[test]
+    fn test_truncated_last_frame_is_completed_by_next_batch() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let first = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            111 <158>1 2022-12-05T08:59:21.8504";
+        let second = "24+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.2 status=503 bytes=0";
+
+        let events =
+            config.with_captured_sentry_events_sync("logplex_token", |destination, _cfg| {
+                process_logs(destination.clone(), first, None, &[]).expect("error processing logs");
+                assert_eq!(destination.counters.get("truncated_frames_total"), 1);
+                assert!(destination.truncated_frame.lock().unwrap().is_some());
+
+                process_logs(destination.clone(), second, None, &[])
+                    .expect("error processing logs");
+                assert!(destination.truncated_frame.lock().unwrap().is_none());
+                assert_eq!(destination.counters.get("truncated_frames_dropped"), 0);
+            });
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1].tags.get("server_name").map(String::as_str),
+            Some("web.2")
+        );
     }
 
-    Some(SentryMessage {
-        tags,
-        fingerprint: vec!["heroku-router-request-timeout".into(), route_name.clone()],
-        message: format!("request timeout on {}\n{}", route_name, logline.text),
-    })
-}
+    #[test]
+    fn test_truncated_frame_is_dropped_when_next_batch_does_not_complete_it() {
+        let _ = initialize_tracing();
+        let config = Config::default();
 
-#[instrument(fields(dsn=?sentry_client.dsn()), skip(sentry_client))]
-fn send_to_sentry(sentry_client: Arc<Client>, message: SentryMessage) {
-    info!(?message, "reporting timeout to sentry");
+        let line = "111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0";
 
-    // uses an empty & new scope instead of the
-    // standard scope which would include details of
-    // this specific service.
-    let mut scope = Scope::default();
-    scope.set_level(Some(Level::Error));
-    for (key, value) in message.tags {
-        scope.set_tag(&key, &value);
+        let events =
+            config.with_captured_sentry_events_sync("logplex_token", |destination, _cfg| {
+                process_logs(
+                    destination.clone(),
+                    "111 <158>1 2022-12-05T08:59:21.8504",
+                    None,
+                    &[],
+                )
+                .expect("error processing logs");
+                process_logs(destination.clone(), line, None, &[]).expect("error processing logs");
+                assert_eq!(destination.counters.get("truncated_frames_dropped"), 1);
+            });
+
+        assert_eq!(events.len(), 1);
     }
 
-    // the fingerprint is used for grouping the messages in sentry.
-    let fingerprint: Vec<_> = message.fingerprint.iter().map(String::as_str).collect();
-    scope.set_fingerprint(Some(&fingerprint));
+    #[test]
+    fn test_unparseable_line_fails_batch() {
+        let _ = initialize_tracing();
+        let config = Config::default();
 
-    let hub = Hub::new(Some(sentry_client), Arc::new(scope));
-    let uuid = hub.capture_message(&message.message, Level::Error);
-    info!(?uuid, last_event_id = ?hub.last_event_id(), "captured message");
-}
+        config.with_captured_sentry_events_sync("logplex_token", |destination, _cfg| {
+            assert!(process_logs(
+                destination.clone(),
+                "10 <158>1 2022-12-05T08:59:21",
+                None,
+                &[]
+            )
+            .is_err());
+            assert_eq!(destination.counters.get("truncated_frames_total"), 0);
+        });
+    }
 
-#[instrument(fields(dsn=?destination.sentry_client.dsn()), skip(destination))]
-pub(crate) fn process_logs(destination: Arc<Destination>, input: &str) -> Result<()> {
-    for line in input.lines() {
-        debug!("handling log line: {}", line);
+    #[test]
+    fn test_sentry_routes() {
+        let _ = initialize_tracing();
 
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-        let (_, log) = parse_log_line(line)
-            .map_err(|err| err.to_owned())
-            .context("could not parse log line")?;
-
-        let parse_pairs = || {
-            parse_key_value_pairs(log.text)
-                .map_err(|err| err.to_owned())
-                .with_context(|| format!("could not parse key value pairs from {}", log.text))
-                .map(|(_, pairs)| pairs)
+        let client = |transport: Arc<dyn sentry::TransportFactory>| {
+            Arc::new(Client::from((
+                "https://public@example.com/1".to_owned(),
+                sentry::ClientOptions {
+                    transport: Some(transport),
+                    ..Default::default()
+                },
+            )))
         };
+        let app_transport = Arc::new(sentry::test::TestTransport::new());
+        let infra_transport = Arc::new(sentry::test::TestTransport::new());
+        let destination = Destination::new("test", client(app_transport.clone()), None)
+            .with_settings("sentry_app_patterns=CRITICAL".parse().unwrap())
+            .with_sentry_routes(vec![(
+                "heroku|*|*|https://public@example.com/2".parse().unwrap(),
+                client(infra_transport.clone()),
+            )]);
 
-        if matches!(log.kind, Kind::Heroku) && log.source == "router" {
-            let map = parse_pairs()?;
+        let input = "
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            CRITICAL something went wrong
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
 
-            debug!(?map, "got router log");
+        process_logs(Arc::new(destination), input, None, &[]).expect("error processing logs");
 
-            let Some(at) = map.get("at") else {
-                warn!(?line, "missing `at` in router log line");
-                continue;
-            };
+        let events = |transport: &Arc<sentry::test::TestTransport>| -> Vec<_> {
+            transport
+                .fetch_and_clear_envelopes()
+                .iter()
+                .filter_map(|envelope| envelope.event().cloned())
+                .collect()
+        };
+        let app_events = events(&app_transport);
+        assert_eq!(app_events.len(), 1);
+        assert_eq!(app_events[0].level, Level::Error);
+        let infra_events = events(&infra_transport);
+        assert_eq!(infra_events.len(), 1);
+        assert_eq!(
+            infra_events[0].fingerprint,
+            vec!["heroku-router-request-timeout", "/"]
+        );
+    }
 
-            if *at != "error" {
-                continue;
-            }
+    #[test]
+    fn test_custom_classifier() {
+        let _ = initialize_tracing();
 
-            let Some(code) = map.get("code") else {
-                warn!(?line, "missing `code` in router `error` log line");
-                continue;
-            };
+        #[derive(Debug)]
+        struct PaymentFailed;
 
-            if *code == "H12" {
-                if let Some(msg) = generate_request_timeout_message(&log, &map) {
-                    send_to_sentry(destination.sentry_client.clone(), msg);
-                }
+        impl LineClassifier for PaymentFailed {
+            fn name(&self) -> &'static str {
+                "payment_failed"
             }
-        } else if let Ok((_, (code, name))) = parse_dyno_error_code(log.text) {
-            if let Some(msg) = generate_dyno_error_message(code, name, &log) {
-                send_to_sentry(destination.sentry_client.clone(), msg);
+
+            fn classify<'a>(
+                &self,
+                _destination: &Destination,
+                line: &ParsedLine<'a>,
+                _batch: &mut BatchState<'a>,
+            ) -> Result<Option<Vec<Action>>> {
+                let Some(order) = line.log.text.strip_prefix("payment failed for order ") else {
+                    return Ok(None);
+                };
+                Ok(Some(vec![Action::Report(SentryMessage::new(
+                    Level::Warning,
+                    vec!["payment-failed".into()],
+                    "payment failed for order %s",
+                    vec![order.into()],
+                ))]))
             }
-        } else if matches!(log.kind, Kind::App)
-            && log.source == "api"
-            && destination.librato_client.is_some()
-        {
-            let Ok((_, (events, _user))) = parse_scaling_event(log.text) else {
-                continue;
-            };
+        }
 
-            let Some(ref librato_client) = destination.librato_client else {
-                continue;
-            };
+        let test_transport = Arc::new(sentry::test::TestTransport::new());
+        let client = Arc::new(Client::from((
+            "https://public@example.com/1".to_owned(),
+            sentry::ClientOptions {
+                transport: Some(test_transport.clone()),
+                ..Default::default()
+            },
+        )));
+        let config = ConfigBuilder::default()
+            .destination("token", Destination::new("test", client, None))
+            .classifier(PaymentFailed)
+            .build()
+            .unwrap();
 
-            debug!("trying to report scaling metrics");
+        let input = "
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            payment failed for order 1234
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            Started GET /some/path
+            ";
 
-            // store the scaling events in a cache so we can regularly re-send them.
-            let mut last_events = destination.last_scaling_events.lock().unwrap();
-            *last_events = Some(events.iter().map(Into::into).collect());
+        process_logs(config.destinations["token"].clone(), input, None, &[])
+            .expect("error processing logs");
 
-            for measurement in generate_librato_scaling_metrics(&log.timestamp, &events) {
-                librato_client.add_measurement(measurement);
-            }
-        }
+        let events: Vec<_> = test_transport
+            .fetch_and_clear_envelopes()
+            .iter()
+            .filter_map(|envelope| envelope.event().cloned())
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, Level::Warning);
+        assert_eq!(
+            events[0].logentry,
+            Some(LogEntry {
+                message: "payment failed for order %s".into(),
+                params: vec!["1234".into()],
+            })
+        );
     }
-    Ok(())
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{config::Config, test_utils::initialize_tracing};
-    use test_case::test_case;
 
     #[test]
-    fn test_process_log() {
+    fn test_unclassified_process_log() {
         let _ = initialize_tracing();
-        let config = Config::default();
+
+        let test_transport = Arc::new(sentry::test::TestTransport::new());
+        let mut destination = Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            None,
+        )
+        .with_settings(
+            "sentry_app_patterns=CRITICAL static_tags=team:payments"
+                .parse()
+                .unwrap(),
+        )
+        .with_sentry_environment("staging");
+        destination.unclassified_sentry_client = Some(Arc::new(Client::from((
+            "https://public@example.com/1".to_owned(),
+            sentry::ClientOptions {
+                transport: Some(test_transport.clone()),
+                ..Default::default()
+            },
+        ))));
 
         let input = "
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            CRITICAL something went wrong
+            111 <190>1 2022-12-05T08:59:21.850424+00:00 host app web.1 - \
+            Started GET /some/path
             111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
-            at=error code=H12 desc=\"Request timeout\" method=GET \
-            path=/ host=myapp.herokuapp.com \
-            request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
-            fwd=\"204.204.204.204\" dyno=web.1 connect=0ms service=30000ms \
-            status=503 bytes=0 protocol=https\
+            at=info method=GET path=/ host=myapp.herokuapp.com status=200 bytes=0
             ";
 
-        let events =
-            config.with_captured_sentry_events_sync("logplex_token", |sentry_client, _cfg| {
-                process_logs(sentry_client, input).expect("error processing logs");
-            });
+        process_logs(Arc::new(destination), input, None, &[]).expect("error processing logs");
 
+        let events: Vec<_> = test_transport
+            .fetch_and_clear_envelopes()
+            .iter()
+            .filter_map(|envelope| envelope.event().cloned())
+            .collect();
         assert_eq!(events.len(), 1);
+        assert_eq!(events[0].level, Level::Info);
+        assert_eq!(
+            events[0].logentry,
+            Some(LogEntry {
+                message: "Started GET /some/path".into(),
+                params: Vec::new(),
+            })
+        );
+        assert_eq!(
+            events[0].fingerprint,
+            vec!["unclassified", "test", "app", "web", "Started"]
+        );
+        // handled like the other sentry reports of the destination.
+        assert_eq!(
+            events[0].tags.get("team").map(String::as_str),
+            Some("payments")
+        );
+        assert_eq!(events[0].environment.as_deref(), Some("staging"));
+    }
+
+    #[test]
+    fn test_distinct_fingerprints_process_log() {
+        let _ = initialize_tracing();
+
+        let line = |path: &str| {
+            format!(
+                "111 <158>1 {} host heroku router - \
+                at=error code=H12 desc=\"Request timeout\" method=GET \
+                path={} host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0",
+                Utc::now().to_rfc3339(),
+                path,
+            )
+        };
+        let input = [line("/a"), line("/b"), line("/a")].join("\n");
+
+        let measurements = Config::default().with_captured_measurements_sync(
+            "logplex_token",
+            DestinationSettings::default(),
+            |destination, _cfg| {
+                process_logs(destination, &input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        let fingerprints: Vec<_> = measurements
+            .iter()
+            .filter(|m| m.name == "sentry.distinct_fingerprints")
+            .map(|m| m.value)
+            .collect();
+        assert_eq!(fingerprints, [2.0]);
+    }
+
+    #[test_case(chrono::Duration::hours(2), true; "lagging")]
+    #[test_case(chrono::Duration::zero(), false; "in time")]
+    fn test_drain_lag_warning_is_throttled_process_log(lag: chrono::Duration, warned: bool) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            None,
+        ));
+        let input = format!(
+            "111 <190>1 {} host app web.1 - Started GET /some/path",
+            (Utc::now() - lag).to_rfc3339(),
+        );
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        // the warning used up the throttle.
+        assert_eq!(destination.drain_lag_log_throttle.allow(), !warned);
+    }
+
+    #[test_case(" service=30000ms", 11; "with service")]
+    #[test_case("", 9; "without service")]
+    fn test_timeout_service_metric_process_log(service: &str, expected_measurements: usize) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            Some(crate::librato::Client::new(
+                "username",
+                "token",
+                None,
+                "invalid_endpoint",
+            )),
+        ));
+
+        let input = format!(
+            "111 <158>1 {} host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0{}",
+            Utc::now().to_rfc3339(),
+            service,
+        );
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        // response bytes, router error, 2 line counts, drain lag,
+        // distinct fingerprints, 3 event latencies and the timeout service
+        // time, also per method.
+        assert_eq!(
+            destination.librato_client.as_ref().unwrap().queue_len(),
+            expected_measurements
+        );
+    }
+
+    #[test_case(
+        "Scaled to web@4:Standard-1X by user heroku.hirefire.api@thermondo.de",
+        "app",
+        "api";
+        "scaling"
+    )]
+    #[test_case(
+        "at=info method=GET path=/ host=myapp.herokuapp.com dyno=web.1 status=200 bytes=10",
+        "heroku",
+        "router";
+        "router"
+    )]
+    fn test_metric_prefix_process_log(text: &str, host: &str, source: &str) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(
+            Destination::new(
+                "test",
+                Arc::new(Client::from(sentry::ClientOptions::default())),
+                Some(crate::librato::Client::new(
+                    "username",
+                    "token",
+                    None,
+                    "invalid_endpoint",
+                )),
+            )
+            .with_settings("metric_prefix=thermondo.web".parse().unwrap()),
+        );
+
+        let line = format!(
+            "<133>1 {} host {} {} - {}",
+            Utc::now().to_rfc3339(),
+            host,
+            source,
+            text
+        );
+        let input = format!("{} {}", line.len(), line);
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        let names: Vec<String> = destination
+            .librato_client
+            .as_ref()
+            .unwrap()
+            .queued_measurements()
+            .into_iter()
+            .map(|measurement| measurement.name)
+            .collect();
+        assert!(!names.is_empty());
+        assert!(
+            names.iter().all(|name| name.starts_with("thermondo.web.")),
+            "{:?}",
+            names
+        );
+    }
+
+    #[test_case("dyno_heartbeats=true", 1; "enabled")]
+    #[test_case("", 0; "disabled")]
+    fn test_dyno_heartbeat_process_log(settings: &str, expected: usize) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(
+            Destination::new(
+                "test",
+                Arc::new(Client::from(sentry::ClientOptions::default())),
+                Some(crate::librato::Client::new(
+                    "username",
+                    "token",
+                    None,
+                    "invalid_endpoint",
+                )),
+            )
+            .with_settings(settings.parse().unwrap()),
+        );
+
+        let input = format!(
+            "100 <190>1 {} host app dramatiqworker.1 - ",
+            Utc::now().to_rfc3339(),
+        );
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        let heartbeats: Vec<_> = destination
+            .librato_client
+            .as_ref()
+            .unwrap()
+            .queued_measurements()
+            .into_iter()
+            .filter(|measurement| measurement.name == "dyno.heartbeat")
+            .collect();
+        assert_eq!(heartbeats.len(), expected);
+        assert!(heartbeats
+            .iter()
+            .all(|measurement| measurement.source == "dramatiqworker.1"));
+    }
+
+    #[test_case("sample_metrics=true", 8; "enabled")]
+    #[test_case("sample_metrics=true metric_sample_rate=0.5", 4; "sampled")]
+    #[test_case("", 0; "disabled")]
+    fn test_sample_metrics_process_log(settings: &str, expected_samples: usize) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(
+            Destination::new(
+                "test",
+                Arc::new(Client::from(sentry::ClientOptions::default())),
+                Some(crate::librato::Client::new(
+                    "username",
+                    "token",
+                    None,
+                    "invalid_endpoint",
+                )),
+            )
+            .with_settings(settings.parse().unwrap()),
+        );
+
+        let line = format!(
+            "200 <134>1 {} host heroku web.1 - source=web.1 \
+            dyno=heroku.145151706.12daf639-fefc-4fba-9c12-d0f27c0a4604 \
+            sample#memory_total=184.68MB sample#memory_rss=158.27MB",
+            Utc::now().to_rfc3339(),
+        );
+        // 4 lines with 2 samples each
+        let input = [line.as_str(); 4].join("\n");
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        let samples: Vec<_> = destination
+            .librato_client
+            .as_ref()
+            .unwrap()
+            .queued_measurements()
+            .into_iter()
+            .filter(|measurement| measurement.name.starts_with("sample."))
+            .collect();
+        assert_eq!(samples.len(), expected_samples);
+        assert!(samples
+            .iter()
+            .all(|measurement| measurement.source == "web.1"));
+    }
+
+    #[test_case("api")]
+    #[test_case("scheduler.1")]
+    fn test_scaling_event_process_log(source: &str) {
+        let _ = initialize_tracing();
+
+        let destination = Arc::new(Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            Some(crate::librato::Client::new(
+                "username",
+                "token",
+                None,
+                "invalid_endpoint",
+            )),
+        ));
+
+        let input = format!(
+            "124 <133>1 {} host app {} - \
+            Scaled to web@4:Standard-1X by user heroku.hirefire.api@thermondo.de",
+            Utc::now().to_rfc3339(),
+            source,
+        );
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+
+        assert!(destination.last_scaling_events.lock().unwrap().is_some());
+        // 2 scaling metrics, 1 scaled-by metric, 2 line count metrics,
+        // 1 drain lag metric, 3 event latency metrics
+        assert_eq!(destination.librato_client.as_ref().unwrap().queue_len(), 9);
+    }
+
+    #[test]
+    fn test_scaling_event_process_log_without_metric_clients() {
+        let _ = initialize_tracing();
+        let destination = Arc::new(Destination::new(
+            "test",
+            Arc::new(Client::from(sentry::ClientOptions::default())),
+            None,
+        ));
+
+        let input = format!(
+            "124 <133>1 {} host app api - \
+            Scaled to web@4:Standard-1X by user heroku.hirefire.api@thermondo.de",
+            Utc::now().to_rfc3339(),
+        );
+
+        process_logs(destination.clone(), &input, None, &[]).expect("error processing logs");
+        assert!(destination.last_scaling_events.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scaling_event_process_log_without_librato() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = format!(
+            "124 <133>1 {} host app api - \
+            Scaled to web@4:Standard-1X by user heroku.hirefire.api@thermondo.de",
+            Utc::now().to_rfc3339(),
+        );
+
+        let measurements = config.with_captured_measurements_sync(
+            "logplex_token",
+            DestinationSettings::default(),
+            |destination, _cfg| {
+                process_logs(destination.clone(), &input, None, &[])
+                    .expect("error processing logs");
+                assert!(destination.last_scaling_events.lock().unwrap().is_some());
+            },
+        );
+
+        let dyno_counts: Vec<_> = measurements
+            .iter()
+            .filter(|m| m.name.starts_with("dyno_count"))
+            .map(|m| (m.name.as_str(), m.source.as_str(), m.value))
+            .collect();
         assert_eq!(
-            events[0].message.as_ref().unwrap(),
-            "request timeout on /\n\
-             at=error code=H12 desc=\"Request timeout\" \
-             method=GET path=/ host=myapp.herokuapp.com \
-             request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
-             fwd=\"204.204.204.204\" dyno=web.1 connect=0ms \
-             service=30000ms status=503 bytes=0 protocol=https"
+            dyno_counts,
+            [
+                ("dyno_count.standard-1x", "web", 4.0),
+                ("dyno_count", "web", 4.0)
+            ]
         );
     }
 
@@ -243,15 +2164,50 @@ mod tests {
 
         let events =
             config.with_captured_sentry_events_sync("logplex_token", |sentry_client, _cfg| {
-                process_logs(sentry_client, input).expect("error processing logs");
+                process_logs(sentry_client, input, None, &[]).expect("error processing logs");
             });
 
         assert_eq!(events.len(), 1);
         assert_eq!(
-            events[0].message.as_ref().unwrap(),
-            "Boot timeout (R10) on web.1\n\
+            events[0].logentry,
+            Some(LogEntry {
+                message: "%s (%s) on %s\n%s".into(),
+                params: vec![
+                    "Boot timeout".into(),
+                    "R10".into(),
+                    "web.1".into(),
+                    "Error R10 (Boot timeout) -> \
+                    Web process failed to bind to $PORT within 60 seconds of launch"
+                        .into(),
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_dyno_error_level_from_settings() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            152 <134>1 2023-04-29T23:11:12.604871+00:00 host heroku web.1 - \
+            Error R14 (Memory quota exceeded)
+            152 <134>1 2023-04-29T23:11:12.604871+00:00 host heroku web.1 - \
             Error R10 (Boot timeout) -> \
-            Web process failed to bind to $PORT within 60 seconds of launch"
+            Web process failed to bind to $PORT within 60 seconds of launch\
+            ";
+
+        let events = config.with_captured_sentry_events_sync_and_settings(
+            "logplex_token",
+            "dyno_error_levels=R14:warning".parse().unwrap(),
+            |destination, _cfg| {
+                process_logs(destination, input, None, &[]).expect("error processing logs");
+            },
+        );
+
+        assert_eq!(
+            events.iter().map(|event| event.level).collect::<Vec<_>>(),
+            vec![Level::Warning, Level::Error]
         );
     }
 
@@ -260,15 +2216,22 @@ mod tests {
         let msg = generate_dyno_error_message(
             "R10",
             "Boot timeout",
+            Level::Error,
             &LogLine {
                 timestamp: "2022-12-05T08:59:21.850424+00:00".parse().unwrap(),
                 source: "web.1",
                 kind: Kind::App,
                 text: "Error R10 (Boot timeout) -> Web process failed to bind to $PORT within 60 seconds of launch"
             }).unwrap();
+        assert_eq!(msg.message, "%s (%s) on %s\n%s");
         assert_eq!(
-            msg.message,
-            "Boot timeout (R10) on web.1\nError R10 (Boot timeout) -> Web process failed to bind to $PORT within 60 seconds of launch",
+            msg.params,
+            vec![
+                "Boot timeout",
+                "R10",
+                "web.1",
+                "Error R10 (Boot timeout) -> Web process failed to bind to $PORT within 60 seconds of launch",
+            ]
         );
         assert_eq!(msg.fingerprint, vec!["heroku-dyno-error-r10", "web.1"]);
         assert_eq!(
@@ -286,18 +2249,20 @@ mod tests {
                 kind: Kind::Heroku,
                 text: "doesn't matter here",
             },
-            &LogMap::from_iter([
-                ("path", "/path/"),
-                ("dyno", "web.1"),
-                ("host", "www.thermondo.de"),
-                ("request_id", "8601b555-6a83-4c12-8269-97c8e32cdb22"),
-            ]),
+            &RouterLog {
+                at: "error",
+                path: Some("/path/"),
+                dyno: Some("web.1"),
+                host: Some("www.thermondo.de"),
+                request_id: Some("8601b555-6a83-4c12-8269-97c8e32cdb22"),
+                ..Default::default()
+            },
+            DEFAULT_TEMPLATERS,
+            TrailingSlash::Keep,
         )
         .unwrap();
-        assert_eq!(
-            msg.message,
-            "request timeout on /path/\ndoesn't matter here"
-        );
+        assert_eq!(msg.message, "request timeout on %s\n%s");
+        assert_eq!(msg.params, vec!["/path/", "doesn't matter here"]);
         assert_eq!(
             msg.fingerprint,
             vec!["heroku-router-request-timeout", "/path/"]
@@ -316,8 +2281,9 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_generate_minimal_timeout_message() {
+    #[test_case(TrailingSlash::Keep, "/path/{number}/"; "keep")]
+    #[test_case(TrailingSlash::Trim, "/path/{number}"; "trim")]
+    fn test_generate_minimal_timeout_message(trailing_slash: TrailingSlash, expected_route: &str) {
         let msg = generate_request_timeout_message(
             &LogLine {
                 timestamp: "2022-12-05T08:59:21.850424+00:00".parse().unwrap(),
@@ -325,36 +2291,83 @@ mod tests {
                 kind: Kind::Heroku,
                 text: "doesn't matter here",
             },
-            &LogMap::from_iter([("path", "/path/1234/"), ("host", "www.thermondo.de")]),
+            &RouterLog {
+                at: "error",
+                path: Some("/path/1234/"),
+                host: Some("www.thermondo.de"),
+                ..Default::default()
+            },
+            DEFAULT_TEMPLATERS,
+            trailing_slash,
         )
         .unwrap();
-        assert_eq!(
-            msg.message,
-            "request timeout on /path/{number}/\ndoesn't matter here"
-        );
+        assert_eq!(msg.message, "request timeout on %s\n%s");
+        assert_eq!(msg.params, vec![expected_route, "doesn't matter here"]);
         assert_eq!(
             msg.fingerprint,
-            vec!["heroku-router-request-timeout", "/path/{number}/"]
+            vec!["heroku-router-request-timeout", expected_route]
         );
         assert_eq!(
             msg.tags,
             HashMap::from_iter([
-                ("transaction".into(), "/path/{number}/".into()),
+                ("transaction".into(), expected_route.into()),
                 ("url".into(), "https://www.thermondo.de/path/1234/".into()),
             ])
         );
     }
 
-    #[test_case("", ""; "1")]
-    #[test_case("/", "/")]
-    #[test_case("/asdf", "/asdf")]
-    #[test_case("/asdf/ddd", "/asdf/ddd")]
-    #[test_case("/asdf/1234/something/", "/asdf/{number}/something/")]
-    #[test_case(
-        "/asdf/8601b555-6a83-4c12-8269-97c8e32cdb22/something/",
-        "/asdf/{uuid}/something/"
-    )]
-    fn test_route_from_path(input: &str, expected: &str) {
-        assert_eq!(route_from_path(input), expected);
+    #[test_case("H15", Some("Idle connection"), "Idle connection"; "with desc")]
+    #[test_case("H15", None, "Idle connection"; "known code")]
+    #[test_case("H99", None, "router error"; "unknown code")]
+    fn test_generate_router_error_message_name(code: &str, desc: Option<&str>, expected: &str) {
+        let msg = generate_router_error_message(
+            code,
+            &LogLine {
+                timestamp: "2022-12-05T08:59:21.850424+00:00".parse().unwrap(),
+                source: "router",
+                kind: Kind::Heroku,
+                text: "doesn't matter here",
+            },
+            &RouterLog {
+                at: "error",
+                code: Some(code),
+                desc,
+                path: Some("/path/"),
+                host: Some("www.thermondo.de"),
+                ..Default::default()
+            },
+            DEFAULT_TEMPLATERS,
+            TrailingSlash::Keep,
+        )
+        .unwrap();
+        assert_eq!(msg.params[0], expected);
+    }
+
+    #[test_case("/", Some("/"))]
+    #[test_case("/asdf/1234/?query=1", Some("/asdf/{number}/"))]
+    #[test_case("invalid path", None)]
+    fn test_route_from_router_log(path: &str, expected: Option<&str>) {
+        assert_eq!(
+            route_from_router_log(
+                &RouterLog {
+                    path: Some(path),
+                    ..Default::default()
+                },
+                DEFAULT_TEMPLATERS,
+                TrailingSlash::Keep
+            )
+            .as_deref(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_route_from_router_log_without_path() {
+        assert!(route_from_router_log(
+            &RouterLog::default(),
+            DEFAULT_TEMPLATERS,
+            TrailingSlash::Keep
+        )
+        .is_none());
     }
 }
@@ -0,0 +1,206 @@
+use crate::log_parser::{
+    parse_offer_extension_number, parse_offer_number, parse_project_reference, parse_sfid,
+};
+use anyhow::{bail, Result};
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// replaces a single element of a URL path with a placeholder,
+/// when it looks like an identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Templater {
+    /// positive integers with at least `min_digits` digits, so short
+    /// segments like API versions stay in the route.
+    Number {
+        min_digits: usize,
+    },
+    Uuid,
+    /// Salesforce IDs
+    Sfid,
+    /// thermondo project references
+    ProjectReference,
+    /// thermondo offer numbers
+    OfferNumber,
+    /// thermondo offer-extension numbers
+    OfferExtensionNumber,
+}
+
+impl Templater {
+    /// the placeholder when `element` matches, otherwise `None`.
+    pub(crate) fn template(&self, element: &str) -> Option<&'static str> {
+        let (matches, placeholder) = match self {
+            Templater::Number { min_digits } => (
+                element.len() >= *min_digits && element.parse::<u64>().is_ok(),
+                "{number}",
+            ),
+            Templater::Uuid => (Uuid::try_parse(element).is_ok(), "{uuid}"),
+            Templater::Sfid => (parse_sfid(element).is_ok(), "{sfid}"),
+            Templater::ProjectReference => (
+                parse_project_reference(element).is_ok(),
+                "{project_reference}",
+            ),
+            Templater::OfferNumber => (parse_offer_number(element).is_ok(), "{offer_number}"),
+            Templater::OfferExtensionNumber => (
+                parse_offer_extension_number(element).is_ok(),
+                "{offer_extension_number}",
+            ),
+        };
+        matches.then_some(placeholder)
+    }
+}
+
+pub(crate) const DEFAULT_NUMBER_MIN_DIGITS: usize = 4;
+
+/// the templaters with the default settings, in the order they are tried.
+#[cfg(test)]
+pub(crate) const DEFAULT_TEMPLATERS: &[Templater] = &templaters(DEFAULT_NUMBER_MIN_DIGITS);
+
+/// the default templaters, with another minimum length for numbers.
+pub(crate) const fn templaters(number_min_digits: usize) -> [Templater; 6] {
+    [
+        Templater::Number {
+            min_digits: number_min_digits,
+        },
+        Templater::Uuid,
+        Templater::Sfid,
+        Templater::ProjectReference,
+        Templater::OfferNumber,
+        Templater::OfferExtensionNumber,
+    ]
+}
+
+/// how to handle a trailing slash in a URL path.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum TrailingSlash {
+    /// `/api/foo/` and `/api/foo` are different routes.
+    #[default]
+    Keep,
+    /// remove trailing slashes, so `/api/foo/` becomes `/api/foo`.
+    /// The root path stays `/`.
+    Trim,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        match input {
+            "keep" => Ok(TrailingSlash::Keep),
+            "trim" => Ok(TrailingSlash::Trim),
+            _ => bail!("unknown trailing slash mode: {}", input),
+        }
+    }
+}
+
+/// generate a route-name from a URL path.
+/// Replaces each element in the path with the placeholder of the first
+/// matching templater.
+pub(crate) fn route_from_path(
+    path: &str,
+    templaters: &[Templater],
+    trailing_slash: TrailingSlash,
+) -> String {
+    let path = match trailing_slash {
+        TrailingSlash::Keep => path,
+        TrailingSlash::Trim => match path.trim_end_matches('/') {
+            "" if path.starts_with('/') => "/",
+            trimmed => trimmed,
+        },
+    };
+
+    let elements: Vec<_> = path
+        .split('/')
+        .map(|el| {
+            templaters
+                .iter()
+                .find_map(|templater| templater.template(el))
+                .unwrap_or(el)
+        })
+        .collect();
+    elements.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(Templater::Number { min_digits: 4 }, "1234", Some("{number}"))]
+    #[test_case(Templater::Number { min_digits: 4 }, "123", None)]
+    #[test_case(Templater::Number { min_digits: 1 }, "2", Some("{number}"))]
+    #[test_case(Templater::Number { min_digits: 1 }, "-1", None)]
+    #[test_case(
+        Templater::Uuid,
+        "8601b555-6a83-4c12-8269-97c8e32cdb22",
+        Some("{uuid}")
+    )]
+    #[test_case(Templater::Uuid, "1234", None)]
+    #[test_case(Templater::Sfid, "0WO1i000003COEnGAO", Some("{sfid}"))]
+    #[test_case(Templater::Sfid, "acceptanceprotocol", None)]
+    #[test_case(Templater::ProjectReference, "WO220VLD", Some("{project_reference}"))]
+    #[test_case(Templater::OfferNumber, "0608656-04", Some("{offer_number}"))]
+    #[test_case(
+        Templater::OfferExtensionNumber,
+        "0608656-04-A",
+        Some("{offer_extension_number}")
+    )]
+    #[test_case(Templater::OfferExtensionNumber, "0608656-04", None)]
+    fn test_templater(templater: Templater, element: &str, expected: Option<&str>) {
+        assert_eq!(templater.template(element), expected);
+    }
+
+    #[test_case("", ""; "1")]
+    #[test_case("/", "/")]
+    #[test_case("/asdf", "/asdf")]
+    #[test_case("/asdf/ddd", "/asdf/ddd")]
+    #[test_case("/asdf/1234/something/", "/asdf/{number}/something/")]
+    #[test_case(
+        "/asdf/8601b555-6a83-4c12-8269-97c8e32cdb22/something/",
+        "/asdf/{uuid}/something/"
+    )]
+    #[test_case("/offer/0608656-04-A/", "/offer/{offer_extension_number}/")]
+    #[test_case("/v1/users/12345", "/v1/users/{number}")]
+    #[test_case("/api/2/users/12345", "/api/2/users/{number}")]
+    fn test_route_from_path(input: &str, expected: &str) {
+        assert_eq!(
+            route_from_path(input, DEFAULT_TEMPLATERS, TrailingSlash::Keep),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_route_from_path_with_custom_templaters() {
+        assert_eq!(
+            route_from_path(
+                "/asdf/1234/8601b555-6a83-4c12-8269-97c8e32cdb22/",
+                &[Templater::Uuid],
+                TrailingSlash::Keep
+            ),
+            "/asdf/1234/{uuid}/"
+        );
+        assert_eq!(
+            route_from_path("/asdf/1234/", &[], TrailingSlash::Keep),
+            "/asdf/1234/"
+        );
+    }
+
+    #[test_case("", ""; "empty")]
+    #[test_case("/", "/"; "root")]
+    #[test_case("//", "/"; "only slashes")]
+    #[test_case("/asdf", "/asdf"; "without slash")]
+    #[test_case("/asdf/", "/asdf"; "with slash")]
+    #[test_case("/asdf/1234//", "/asdf/{number}"; "with multiple slashes")]
+    fn test_route_from_path_trim_trailing_slash(input: &str, expected: &str) {
+        assert_eq!(
+            route_from_path(input, DEFAULT_TEMPLATERS, TrailingSlash::Trim),
+            expected
+        );
+    }
+
+    #[test_case("keep", Some(TrailingSlash::Keep))]
+    #[test_case("trim", Some(TrailingSlash::Trim))]
+    #[test_case("other", None)]
+    fn test_parse_trailing_slash(input: &str, expected: Option<TrailingSlash>) {
+        assert_eq!(input.parse().ok(), expected);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex,
+};
+
+/// fingerprints we remember per sampler, so new fingerprints, like from
+/// messages with ids, can't grow the credits without limit.
+const MAX_FINGERPRINTS: usize = 10_000;
+
+/// samples sentry events per fingerprint, or sample metric lines per dyno.
+///
+/// The first event of each fingerprint is always sent, so rare errors
+/// get through. After that only the share `rate` of the events with the
+/// same fingerprint is sent, spread evenly.
+#[derive(Debug)]
+pub(crate) struct FingerprintSampler {
+    /// fingerprint hash -> how many events we may send.
+    credits: Mutex<HashMap<u64, f64>>,
+    /// when a new fingerprint would exceed this, we forget all of them.
+    /// That only means the next event of each is sent again.
+    max_fingerprints: usize,
+}
+
+impl Default for FingerprintSampler {
+    fn default() -> Self {
+        Self {
+            credits: Mutex::new(HashMap::new()),
+            max_fingerprints: MAX_FINGERPRINTS,
+        }
+    }
+}
+
+impl FingerprintSampler {
+    /// `true` when the event with this fingerprint should be sent.
+    pub(crate) fn sample(&self, fingerprint: impl Hash, rate: f64) -> bool {
+        if rate >= 1.0 {
+            return true;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        fingerprint.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let mut credits = self.credits.lock().unwrap();
+        if credits.len() >= self.max_fingerprints && !credits.contains_key(&hash) {
+            credits.clear();
+        }
+        let credit = credits.entry(hash).or_insert(1.0);
+        if *credit >= 1.0 {
+            *credit -= 1.0;
+            *credit += rate;
+            true
+        } else {
+            *credit += rate;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    #[test_case(1.0, 10; "send all")]
+    #[test_case(0.5, 5; "half")]
+    #[test_case(0.1, 1; "tenth")]
+    #[test_case(0.0, 1; "only first")]
+    fn test_sample(rate: f64, expected: usize) {
+        let sampler = FingerprintSampler::default();
+
+        let sent = (0..10)
+            .filter(|_| sampler.sample(["some", "fingerprint"], rate))
+            .count();
+
+        assert_eq!(sent, expected);
+    }
+
+    #[test]
+    fn test_first_event_per_fingerprint_is_sent() {
+        let sampler = FingerprintSampler::default();
+
+        assert!(sampler.sample("common", 0.0));
+        assert!(!sampler.sample("common", 0.0));
+        assert!(sampler.sample("rare", 0.0));
+    }
+
+    #[test]
+    fn test_fingerprints_are_limited() {
+        let sampler = FingerprintSampler {
+            max_fingerprints: 2,
+            ..Default::default()
+        };
+
+        assert!(sampler.sample("first", 0.0));
+        assert!(sampler.sample("second", 0.0));
+        assert!(!sampler.sample("first", 0.0));
+        assert!(sampler.sample("third", 0.0));
+
+        assert_eq!(sampler.credits.lock().unwrap().len(), 1);
+        assert!(sampler.sample("first", 0.0));
+    }
+}
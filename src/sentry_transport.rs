@@ -0,0 +1,407 @@
+use crate::{
+    http_client::{self, REQUEST_ID},
+    stats::Counters,
+    throttle::Throttle,
+};
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use sentry::{ClientOptions, Envelope};
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// envelopes waiting to be sent, like in the default sentry transport.
+const QUEUE_LEN: usize = 30;
+/// envelopes kept while the queue is full or sentry rate limits us.
+const MAX_DEAD_LETTERS: usize = 100;
+/// failed sends of an envelope before we drop it.
+const MAX_ATTEMPTS: u32 = 3;
+/// how often kept envelopes are retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// when sentry answers with `429` without saying for how long.
+const DEFAULT_RATE_LIMIT: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// creates a [`Transport`] per sentry client, all counting into
+/// the same counters.
+#[derive(Debug)]
+pub(crate) struct TransportFactory {
+    counters: Arc<Counters>,
+}
+
+impl TransportFactory {
+    pub(crate) fn new(counters: Arc<Counters>) -> Self {
+        Self { counters }
+    }
+}
+
+impl sentry::TransportFactory for TransportFactory {
+    fn create_transport(&self, options: &ClientOptions) -> Arc<dyn sentry::Transport> {
+        Arc::new(Transport::new(options, self.counters.clone()))
+    }
+}
+
+enum Task {
+    Send(Vec<u8>),
+    Flush(SyncSender<()>),
+    Shutdown,
+}
+
+/// like the default sentry transport, but envelopes that don't fit into
+/// the queue, or arrive while sentry rate limits us, are kept and retried
+/// instead of silently dropped. Envelopes we give up on are counted as
+/// `sentry_envelopes_dropped`.
+pub(crate) struct Transport {
+    sender: SyncSender<Task>,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// the state shared by the capturing threads and the sending thread.
+struct Shared {
+    /// serialized envelopes with their failed send attempts, oldest first.
+    dead_letters: Mutex<VecDeque<(Vec<u8>, u32)>>,
+    rate_limited_until: Mutex<Option<Instant>>,
+    counters: Arc<Counters>,
+    drop_warnings: Throttle,
+}
+
+impl Shared {
+    fn new(counters: Arc<Counters>) -> Self {
+        Self {
+            dead_letters: Mutex::new(VecDeque::new()),
+            rate_limited_until: Mutex::new(None),
+            counters,
+            drop_warnings: Throttle::new(WARNING_INTERVAL),
+        }
+    }
+
+    fn is_rate_limited(&self) -> bool {
+        self.rate_limited_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    fn rate_limit(&self, duration: Duration) {
+        debug!(?duration, "rate limited by sentry");
+        self.counters.increment("sentry_rate_limits");
+        *self.rate_limited_until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+
+    /// keep the envelope to retry it, dropping the oldest one when full.
+    fn keep(&self, body: Vec<u8>, attempts: u32) {
+        let mut dead_letters = self.dead_letters.lock().unwrap();
+        if dead_letters.len() >= MAX_DEAD_LETTERS {
+            dead_letters.pop_front();
+            self.dropped("too many envelopes waiting for sentry");
+        }
+        dead_letters.push_back((body, attempts));
+    }
+
+    fn dropped(&self, reason: &'static str) {
+        self.counters.increment("sentry_envelopes_dropped");
+        if self.drop_warnings.allow() {
+            warn!(reason, "dropping sentry envelopes");
+        }
+    }
+}
+
+impl Transport {
+    fn new(options: &ClientOptions, counters: Arc<Counters>) -> Self {
+        let dsn = options.dsn.as_ref().expect("sentry transport without DSN");
+        let url = dsn.envelope_api_url().to_string();
+        let auth = dsn.to_auth(Some(&options.user_agent)).to_string();
+
+        let (sender, receiver) = sync_channel(QUEUE_LEN);
+        let shared = Arc::new(Shared::new(counters));
+        let handle = thread::Builder::new()
+            .name("sentry-transport".into())
+            .spawn({
+                let shared = shared.clone();
+                move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("could not build sentry transport runtime");
+                    runtime.block_on(
+                        Worker {
+                            http_client: http_client::builder()
+                                .timeout(REQUEST_TIMEOUT)
+                                .build()
+                                .expect("could not build HTTP client"),
+                            url,
+                            auth,
+                            shared,
+                        }
+                        .run(receiver),
+                    );
+                }
+            })
+            .ok();
+
+        Self {
+            sender,
+            shared,
+            handle,
+        }
+    }
+}
+
+impl sentry::Transport for Transport {
+    fn send_envelope(&self, envelope: Envelope) {
+        let mut body = Vec::new();
+        if let Err(err) = envelope.to_writer(&mut body) {
+            warn!(?err, "could not serialize sentry envelope");
+            return;
+        }
+
+        if self.shared.is_rate_limited() {
+            self.shared.keep(body, 0);
+            return;
+        }
+        match self.sender.try_send(Task::Send(body)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Task::Send(body))) => self.shared.keep(body, 0),
+            Err(_) => self.shared.dropped("sentry transport is shut down"),
+        }
+    }
+
+    /// waits until the queue is sent, and tries the kept envelopes
+    /// once more.
+    fn flush(&self, timeout: Duration) -> bool {
+        let (sender, receiver) = sync_channel(1);
+        if self.sender.send(Task::Flush(sender)).is_err() {
+            return false;
+        }
+        receiver.recv_timeout(timeout).is_ok()
+    }
+}
+
+impl Drop for Transport {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Task::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// sends the envelopes on the transport thread.
+struct Worker {
+    http_client: reqwest::Client,
+    url: String,
+    auth: String,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    async fn run(self, receiver: Receiver<Task>) {
+        let mut last_retry = Instant::now();
+        loop {
+            match receiver.recv_timeout(RETRY_INTERVAL) {
+                Ok(Task::Send(body)) => self.send(body, 0).await,
+                Ok(Task::Flush(done)) => {
+                    self.retry_dead_letters().await;
+                    last_retry = Instant::now();
+                    let _ = done.send(());
+                }
+                Ok(Task::Shutdown) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            if last_retry.elapsed() >= RETRY_INTERVAL {
+                self.retry_dead_letters().await;
+                last_retry = Instant::now();
+            }
+        }
+    }
+
+    async fn retry_dead_letters(&self) {
+        if self.shared.is_rate_limited() {
+            return;
+        }
+        let dead_letters: Vec<_> = self.shared.dead_letters.lock().unwrap().drain(..).collect();
+        for (body, attempts) in dead_letters {
+            self.send(body, attempts).await;
+        }
+    }
+
+    async fn send(&self, body: Vec<u8>, attempts: u32) {
+        if self.shared.is_rate_limited() {
+            self.shared.keep(body, attempts);
+            return;
+        }
+
+        let response = self
+            .http_client
+            .post(&self.url)
+            .header("X-Sentry-Auth", &self.auth)
+            .header(&REQUEST_ID, http_client::new_request_id())
+            .body(body.clone())
+            .send()
+            .await;
+
+        match response {
+            Ok(response) => {
+                if let Some(duration) = rate_limit_from_response(&response) {
+                    self.shared.rate_limit(duration);
+                }
+                match response.status() {
+                    status if status.is_success() => {}
+                    StatusCode::TOO_MANY_REQUESTS => self.shared.keep(body, attempts),
+                    status if status.is_client_error() => {
+                        debug!(?status, "sentry rejected envelope");
+                        self.shared.dropped("sentry rejected envelopes");
+                    }
+                    status => self.failed(body, attempts, &status),
+                }
+            }
+            Err(err) => self.failed(body, attempts, &err.without_url()),
+        }
+    }
+
+    fn failed(&self, body: Vec<u8>, attempts: u32, err: &dyn std::fmt::Debug) {
+        debug!(?err, attempts, "could not send sentry envelope");
+        if attempts + 1 < MAX_ATTEMPTS {
+            self.shared.keep(body, attempts + 1);
+        } else {
+            self.shared.dropped("sending to sentry failed repeatedly");
+        }
+    }
+}
+
+/// how long sentry asks us to stop sending, from its rate limit headers,
+/// or the default for a bare `429`.
+fn rate_limit_from_response(response: &reqwest::Response) -> Option<Duration> {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    // like `60:error;transaction:key, 2700:default:organization`,
+    // we don't rate limit by category and wait for the longest one.
+    if let Some(limits) = header("x-sentry-rate-limits") {
+        return limits
+            .split(',')
+            .filter_map(|limit| limit.trim().split(':').next()?.parse::<f64>().ok())
+            .reduce(f64::max)
+            .map(|secs| Duration::from_secs(secs.ceil() as u64));
+    }
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    Some(
+        header(RETRY_AFTER.as_str())
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .map(|secs| Duration::from_secs(secs.ceil() as u64))
+            .unwrap_or(DEFAULT_RATE_LIMIT),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sentry::{protocol::Event, Transport as _};
+
+    fn transport(server: &mockito::Server, counters: &Arc<Counters>) -> Transport {
+        let options = ClientOptions {
+            dsn: Some(
+                format!("http://public@{}/1", server.host_with_port())
+                    .parse()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        Transport::new(&options, counters.clone())
+    }
+
+    fn envelope() -> Envelope {
+        Envelope::from(Event::default())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_envelopes_are_kept() {
+        let mut server = mockito::Server::new_async().await;
+        let rate_limited = server
+            .mock("POST", "/api/1/envelope/")
+            .with_status(429)
+            .with_header("retry-after", "0")
+            .expect(1)
+            .create_async()
+            .await;
+        let accepted = server
+            .mock("POST", "/api/1/envelope/")
+            .with_status(200)
+            .expect(1)
+            .create_async()
+            .await;
+
+        let counters = Arc::new(Counters::default());
+        let transport = transport(&server, &counters);
+        transport.send_envelope(envelope());
+        assert!(transport.flush(Duration::from_secs(5)));
+
+        rate_limited.assert_async().await;
+        accepted.assert_async().await;
+        assert_eq!(counters.get("sentry_rate_limits"), 1);
+        assert_eq!(counters.get("sentry_envelopes_dropped"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_failed_envelopes_are_retried_and_dropped() {
+        let mut server = mockito::Server::new_async().await;
+        let failed = server
+            .mock("POST", "/api/1/envelope/")
+            .with_status(503)
+            .expect(MAX_ATTEMPTS as usize)
+            .create_async()
+            .await;
+
+        let counters = Arc::new(Counters::default());
+        let transport = transport(&server, &counters);
+        transport.send_envelope(envelope());
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(transport.flush(Duration::from_secs(5)));
+        }
+
+        failed.assert_async().await;
+        assert_eq!(counters.get("sentry_envelopes_dropped"), 1);
+        assert!(transport.shared.dead_letters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dead_letters_are_bounded() {
+        let counters = Arc::new(Counters::default());
+        let shared = Shared::new(counters.clone());
+
+        for _ in 0..MAX_DEAD_LETTERS + 5 {
+            shared.keep(Vec::new(), 0);
+        }
+
+        assert_eq!(shared.dead_letters.lock().unwrap().len(), MAX_DEAD_LETTERS);
+        assert_eq!(counters.get("sentry_envelopes_dropped"), 5);
+    }
+
+    #[tokio::test]
+    async fn test_envelopes_are_kept_while_rate_limited() {
+        let server = mockito::Server::new_async().await;
+        let counters = Arc::new(Counters::default());
+        let transport = transport(&server, &counters);
+
+        transport.shared.rate_limit(Duration::from_secs(60));
+        transport.send_envelope(envelope());
+
+        assert_eq!(transport.shared.dead_letters.lock().unwrap().len(), 1);
+        assert_eq!(counters.get("sentry_envelopes_dropped"), 0);
+    }
+}
@@ -1,47 +1,268 @@
-use crate::{config::Config, extractors::LogplexDrainToken, reporter::process_logs};
+use crate::{
+    config::{Config, ConfigHandle},
+    extractors::{LogplexDrainToken, LogplexFrameId, LogplexMsgCount, XEnvironment},
+    metrics::generate_librato_lines_unparsed_metric,
+    reporter::process_logs,
+};
 use anyhow::Context as _;
 use axum::{
     body::{self, Body},
-    extract::State,
+    extract::{FromRef, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Json, Router,
+};
+use axum_extra::{
+    headers::{
+        authorization::{Basic, Bearer},
+        Authorization, ContentType,
+    },
+    TypedHeader,
 };
-use axum_extra::TypedHeader;
-use std::sync::Arc;
-use tracing::{debug, instrument, warn};
+use chrono::Utc;
+use serde_json::json;
+use std::{sync::Arc, time::Instant};
+use tracing::{debug, info, instrument, warn};
+
+/// only keep the start of a logplex token, so we can log it
+/// without leaking the whole token.
+fn truncate_token(logplex_token: &str) -> String {
+    const VISIBLE_CHARS: usize = 8;
+
+    if logplex_token.chars().count() <= VISIBLE_CHARS {
+        logplex_token.to_owned()
+    } else {
+        format!(
+            "{}...",
+            logplex_token
+                .chars()
+                .take(VISIBLE_CHARS)
+                .collect::<String>()
+        )
+    }
+}
+
+/// the state of the app, handlers get the current config
+/// with `State<Arc<Config>>`.
+#[derive(Clone)]
+pub(crate) struct AppState(Arc<ConfigHandle>);
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.0.current()
+    }
+}
 
-pub(crate) fn build_app(config: Arc<Config>) -> Router {
+pub fn build_app(config: Arc<Config>) -> Router {
+    build_reloadable_app(Arc::new(ConfigHandle::new(config)))
+}
+
+/// like [`build_app`], `POST /reload` replaces the config of the handle,
+/// so the background tasks sharing it see the new one.
+pub(crate) fn build_reloadable_app(config: Arc<ConfigHandle>) -> Router {
     Router::new()
         .route("/ht", get(health_check))
+        .route("/stats", get(stats))
         .route("/", post(handle_logs))
-        .with_state(config)
+        .route("/debug/log-level", post(set_log_level))
+        .route("/reload", post(reload))
+        .with_state(AppState(config))
 }
 
 pub(crate) async fn health_check() -> impl IntoResponse {
     StatusCode::OK
 }
 
+/// internal counters for each destination, for debugging and monitoring.
+/// Also shows the effective backends per destination, to verify the mappings,
+/// and how long the oldest librato measurement is queued, to detect stuck flushes.
+/// Only available with `LOG_REPORTER_DEBUG_ENDPOINTS`, since it lists the mappings.
+pub(crate) async fn stats(State(config): State<Arc<Config>>) -> Response {
+    if !config.debug_endpoints {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let destinations: serde_json::Map<_, _> = config
+        .all_destinations()
+        .map(|destination| {
+            (
+                destination.name.clone(),
+                json!({
+                    "counters": destination.counters.snapshot(),
+                    "environment": destination.sentry_environment,
+                    "librato": destination.librato_client.is_some(),
+                    "librato_oldest_measurement_age_secs": destination
+                        .librato_client
+                        .as_ref()
+                        .and_then(|client| client.oldest_measurement_age())
+                        .map(|age| age.as_secs_f64()),
+                    "librato_dropped_measurements": destination
+                        .librato_client
+                        .as_ref()
+                        .map(|client| client.dropped_measurements()),
+                    "shadow_librato": destination.shadow_librato_clients.len(),
+                    "forward": destination.forward_client.is_some(),
+                }),
+            )
+        })
+        .collect();
+
+    Json(json!({
+        "counters": config.counters.snapshot(),
+        "destinations": destinations,
+    }))
+    .into_response()
+}
+
+/// replace the log filter at runtime, the body uses the `RUST_LOG` format,
+/// like `log_reporter=debug`.
+/// Only available with `LOG_REPORTER_DEBUG_ENDPOINTS`.
+pub(crate) async fn set_log_level(
+    State(config): State<Arc<Config>>,
+    directives: String,
+) -> impl IntoResponse {
+    let Some(reloader) = config
+        .log_filter_reloader
+        .as_ref()
+        .filter(|_| config.debug_endpoints)
+    else {
+        return StatusCode::NOT_FOUND;
+    };
+
+    match reloader.reload(directives.trim()) {
+        Ok(()) => {
+            warn!(directives, "log filter changed");
+            StatusCode::OK
+        }
+        Err(err) => {
+            debug!(?err, directives, "invalid log filter");
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+/// load the config from the environment again and swap it in,
+/// answers with the summary of the loaded mappings.
+/// Only available with `LOG_REPORTER_ADMIN_TOKEN`, as bearer token.
+pub(crate) async fn reload(
+    State(AppState(handle)): State<AppState>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Response {
+    let Some(admin_token) = handle.current().admin_token.clone() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if !authorization
+        .is_some_and(|TypedHeader(Authorization(bearer))| admin_token.matches(bearer.token()))
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match handle.reload() {
+        Ok(config) => {
+            let summary = config.mappings_summary();
+            info!(?summary, "config reloaded");
+            Json(json!({
+                "loaded": summary.loaded,
+                "invalid": summary.invalid,
+                "disabled": summary.disabled,
+                "with_librato": summary.with_librato,
+            }))
+            .into_response()
+        }
+        Err(err) => {
+            warn!(?err, "could not reload config, keeping the current one");
+            (StatusCode::UNPROCESSABLE_ENTITY, format!("{:#}", err)).into_response()
+        }
+    }
+}
+
 #[allow(
     // open bug in tokio/tracing, see:
     // https://github.com/tokio-rs/tracing/issues/2503
-    clippy::let_with_type_underscore
+    clippy::let_with_type_underscore,
+    // every optional header is its own extractor.
+    clippy::too_many_arguments
+)]
+#[instrument(
+    skip(basic_auth, content_type, frame_id, msg_count, environment, body, config),
+    fields(
+        frame_id = frame_id.as_ref().map(|TypedHeader(id)| id.as_str()),
+        msg_count = msg_count.as_ref().map(|TypedHeader(LogplexMsgCount(count))| count),
+    )
 )]
-#[instrument(skip(body, config))]
 pub(crate) async fn handle_logs(
-    TypedHeader(logplex_token): TypedHeader<LogplexDrainToken>,
+    logplex_token: Option<TypedHeader<LogplexDrainToken>>,
+    frame_id: Option<TypedHeader<LogplexFrameId>>,
+    msg_count: Option<TypedHeader<LogplexMsgCount>>,
+    basic_auth: Option<TypedHeader<Authorization<Basic>>>,
+    content_type: Option<TypedHeader<ContentType>>,
+    environment: Option<TypedHeader<XEnvironment>>,
     State(config): State<Arc<Config>>,
     body: Body,
 ) -> impl IntoResponse {
-    let destination = match config.destinations.get(logplex_token.as_str()) {
-        Some(dest) => dest,
-        None => {
-            debug!(?logplex_token, "unknown logplex token");
+    let content_type = content_type.map(|TypedHeader(content_type)| content_type.to_string());
+    if !config.accepts_content_type(content_type.as_deref()) {
+        debug!(content_type, "unsupported content type");
+        config
+            .counters
+            .increment("unsupported_content_type_requests_total");
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE;
+    }
+
+    // the token header is preferred, basic auth is the fallback for
+    // log sources that can't set custom headers.
+    let logplex_token = match (&logplex_token, &basic_auth) {
+        (Some(TypedHeader(logplex_token)), _) => logplex_token.as_str(),
+        (None, Some(TypedHeader(Authorization(credentials)))) => {
+            match config
+                .logplex_token_for_basic_auth(credentials.username(), credentials.password())
+            {
+                Some(logplex_token) => logplex_token,
+                None => {
+                    debug!(
+                        username = credentials.username(),
+                        "invalid basic auth credentials"
+                    );
+                    return StatusCode::UNAUTHORIZED;
+                }
+            }
+        }
+        (None, None) => {
+            debug!("missing logplex token and basic auth");
             return StatusCode::BAD_REQUEST;
         }
     };
 
+    let (destination, is_fallback) = match config.destinations.get(logplex_token) {
+        Some(dest) => (dest, false),
+        None => {
+            config.counters.increment("unknown_token_requests_total");
+            if config.unknown_token_log_throttle.allow() {
+                warn!(
+                    logplex_token = truncate_token(logplex_token),
+                    "unknown logplex token, is a log drain missing its mapping?"
+                );
+            } else {
+                debug!(
+                    logplex_token = truncate_token(logplex_token),
+                    "unknown logplex token"
+                );
+            }
+            match config.fallback_destination {
+                Some(ref dest) => (dest, true),
+                None => return StatusCode::BAD_REQUEST,
+            }
+        }
+    };
+
+    // muted mappings still answer with `200`, so logplex doesn't retry.
+    if !destination.settings.enabled {
+        debug!("ignoring batch for disabled mapping");
+        destination.counters.increment("batches_muted");
+        return StatusCode::OK;
+    }
+
     let body = match body::to_bytes(body, usize::MAX)
         .await
         .context("could not fetch POST body")
@@ -53,6 +274,17 @@ pub(crate) async fn handle_logs(
         }
     };
 
+    // logplex retries deliveries it thinks failed, we don't want
+    // to report the same logs twice.
+    if config
+        .recent_batches
+        .check_and_insert((logplex_token, &body))
+    {
+        debug!("ignoring duplicate batch");
+        destination.counters.increment("duplicate_batches");
+        return StatusCode::OK;
+    }
+
     // move decoding, parsing and creating the logmessage
     // into the main background rayon threadpool.
     //
@@ -65,7 +297,11 @@ pub(crate) async fn handle_logs(
         let destination = destination.clone();
         let config = config.clone();
         let runtime = tokio::runtime::Handle::current();
-        let task_wait_ticket = config.new_waitgroup_ticket();
+        let task_wait_ticket = config.new_processing_ticket();
+        let truncated_token = truncate_token(logplex_token);
+        let frame_id = frame_id.map(|TypedHeader(id)| id.as_str().to_owned());
+        let msg_count = msg_count.map(|TypedHeader(LogplexMsgCount(count))| count);
+        let environment = environment.map(|TypedHeader(env)| env.as_str().to_owned());
         rayon::spawn(move || {
             let _guard = runtime.enter(); // so we can use tokio::spawn in this rayon task
 
@@ -77,8 +313,46 @@ pub(crate) async fn handle_logs(
                 }
             };
 
-            if let Err(err) = process_logs(destination, body_text) {
-                warn!("error processing logs: {:?}", err);
+            let started = Instant::now();
+            // the fallback destination is shared by all unknown tokens.
+            let token_tag = [("logplex_token", truncated_token.as_str())];
+            let tags: &[_] = if is_fallback { &token_tag } else { &[] };
+            match process_logs(destination.clone(), body_text, environment.as_deref(), tags) {
+                Ok(summary) => {
+                    // the declared count includes lines we skip on purpose,
+                    // but any difference is worth a look.
+                    if let Some(msg_count) = msg_count {
+                        if msg_count != summary.lines {
+                            warn!(
+                                frame_id,
+                                msg_count,
+                                lines = summary.lines,
+                                "parsed line count differs from logplex-msg-count"
+                            );
+                            destination.counters.increment("msg_count_mismatches");
+                        }
+
+                        let unparsed = msg_count.saturating_sub(summary.lines);
+                        if unparsed > 0 && destination.has_metric_clients() {
+                            if let Some(measurement) = generate_librato_lines_unparsed_metric(
+                                &Utc::now().fixed_offset(),
+                                unparsed,
+                            ) {
+                                destination.add_measurement(measurement);
+                            }
+                        }
+                    }
+
+                    config.access_log.record(
+                        &truncated_token,
+                        frame_id.as_deref(),
+                        msg_count,
+                        body.len(),
+                        &summary,
+                        started.elapsed(),
+                    );
+                }
+                Err(err) => warn!("error processing logs: {:?}", err),
             }
             // we actually don't need the `drop` here,
             // we only use it so `task_wait_ticket` will be moved into
@@ -93,13 +367,27 @@ pub(crate) async fn handle_logs(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{extractors::LOGPLEX_DRAIN_TOKEN, test_utils::initialize_tracing};
+    use crate::{
+        config::{BasicAuthMapping, Destination, DestinationSettings},
+        extractors::{LOGPLEX_DRAIN_TOKEN, LOGPLEX_FRAME_ID, LOGPLEX_MSG_COUNT},
+        log_parser::ScalingEvent,
+        metrics::{generate_librato_scaling_metrics, DynoCountGrouping, MetricNameCase},
+        test_utils::initialize_tracing,
+    };
     use axum::{
         body::Body,
         http::{Request, StatusCode},
     };
+    use std::sync::Mutex;
+    use test_case::test_case;
     use tower::util::ServiceExt;
 
+    #[test_case("short", "short")]
+    #[test_case("d.12345678-1234", "d.123456...")]
+    fn test_truncate_token(input: &str, expected: &str) {
+        assert_eq!(truncate_token(input), expected);
+    }
+
     #[tokio::test]
     async fn test_health_check() {
         let app = build_app(Arc::new(Config::default()));
@@ -112,6 +400,112 @@ mod tests {
         assert_eq!(response.status(), StatusCode::OK)
     }
 
+    #[tokio::test]
+    async fn test_stats_without_debug_endpoints() {
+        let response = build_app(Arc::new(Config::default()))
+            .oneshot(Request::get("/stats").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let config = Config::builder().debug_endpoints(true).build().unwrap();
+
+        config
+            .with_captured_sentry_events_async("token", |destination, config| async move {
+                destination.counters.increment("something");
+
+                let app = build_app(config);
+                let response = app
+                    .oneshot(Request::get("/stats").body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), StatusCode::OK);
+                let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                assert_eq!(
+                    body,
+                    json!({
+                        "counters": {},
+                        "destinations": {"token": {
+                            "counters": {"something": 1},
+                            "environment": null,
+                            "librato": false,
+                            "librato_oldest_measurement_age_secs": null,
+                            "librato_dropped_measurements": null,
+                            "shadow_librato": 0,
+                            "forward": false,
+                        }},
+                    })
+                );
+            })
+            .await;
+    }
+
+    #[test_case(None, None, StatusCode::NOT_FOUND; "disabled")]
+    #[test_case(Some("secret"), None, StatusCode::UNAUTHORIZED; "missing token")]
+    #[test_case(Some("secret"), Some("wrong"), StatusCode::UNAUTHORIZED; "wrong token")]
+    #[tokio::test]
+    async fn test_reload_auth(
+        admin_token: Option<&str>,
+        bearer_token: Option<&str>,
+        expected: StatusCode,
+    ) {
+        let config = Config::builder()
+            .admin_token(admin_token.map(ToOwned::to_owned))
+            .build()
+            .unwrap();
+
+        let mut request = Request::post("/reload");
+        if let Some(bearer_token) = bearer_token {
+            request = request.header("authorization", format!("Bearer {}", bearer_token));
+        }
+        let response = build_app(Arc::new(config))
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), expected);
+    }
+
+    #[test_case(false, "debug", StatusCode::NOT_FOUND; "disabled")]
+    #[test_case(true, "debug", StatusCode::OK; "valid filter")]
+    #[test_case(true, "log_reporter=nonsense", StatusCode::BAD_REQUEST; "invalid filter")]
+    #[tokio::test]
+    async fn test_set_log_level(debug_endpoints: bool, body: &str, expected: StatusCode) {
+        let mut config = Config::builder()
+            .debug_endpoints(debug_endpoints)
+            .build()
+            .unwrap();
+        // the handle only works while the layer is alive.
+        let (_layer, handle) =
+            tracing_subscriber::reload::Layer::<_, tracing_subscriber::Registry>::new(
+                tracing_subscriber::EnvFilter::new("info"),
+            );
+        config.log_filter_reloader =
+            Some(crate::config::LogFilterReloader::new(move |directives| {
+                handle.reload(tracing_subscriber::EnvFilter::try_new(directives)?)?;
+                Ok(())
+            }));
+
+        let response = build_app(Arc::new(config))
+            .oneshot(
+                Request::post("/debug/log-level")
+                    .body(Body::from(body.to_owned()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), expected);
+    }
+
     #[tokio::test]
     async fn test_get_fails() {
         let app = build_app(Arc::new(Config::default()));
@@ -170,6 +564,7 @@ mod tests {
                     .unwrap();
 
                 assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+                assert_eq!(config.counters.get("unknown_token_requests_total"), 1);
                 let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
                     .await
                     .unwrap();
@@ -178,6 +573,62 @@ mod tests {
             .await;
     }
 
+    #[tokio::test]
+    async fn test_post_unknown_drain_token_to_default_destination() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let test_sentry_transport = config
+            .clone()
+            .with_captured_sentry_transport_async("real_token", |destination, config| async move {
+                let mut config = (*config).clone();
+                config.fallback_destination = Some(Arc::new(
+                    config.new_fallback_destination(destination.sentry_client.clone()),
+                ));
+                let config = Arc::new(config);
+
+                for token in ["d.12345678-unknown", "d.87654321-unknown"] {
+                    let response = build_app(config.clone())
+                        .oneshot(
+                            Request::post("/")
+                                .header(&LOGPLEX_DRAIN_TOKEN, token)
+                                .body(Body::from(input))
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
+                }
+                assert_eq!(config.counters.get("unknown_token_requests_total"), 2);
+            })
+            .await;
+
+        // wait for async tasks to finish
+        config.shutdown().await;
+
+        let events: Vec<_> = test_sentry_transport
+            .fetch_and_clear_envelopes()
+            .iter()
+            .filter_map(|envelope| envelope.event().cloned())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|event| event.environment.as_deref() == Some("unmapped")));
+        let mut tokens: Vec<_> = events
+            .iter()
+            .filter_map(|event| event.tags.get("logplex_token"))
+            .collect();
+        tokens.sort();
+        assert_eq!(tokens, ["d.123456...", "d.876543..."]);
+    }
+
     #[tokio::test]
     async fn test_post_missing_drain_token() {
         let _ = initialize_tracing();
@@ -190,12 +641,270 @@ mod tests {
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
-        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
-            .await
+    }
+
+    #[test_case(None, StatusCode::UNSUPPORTED_MEDIA_TYPE; "missing")]
+    #[test_case(Some("text/plain"), StatusCode::UNSUPPORTED_MEDIA_TYPE; "other")]
+    #[test_case(Some("application/logplex-1"), StatusCode::OK; "accepted")]
+    #[tokio::test]
+    async fn test_post_content_type_allowlist(content_type: Option<&str>, expected: StatusCode) {
+        let _ = initialize_tracing();
+        let config = Config::builder()
+            .accepted_content_types(vec!["application/logplex-1".into()])
+            .build()
             .unwrap();
-        let body = std::str::from_utf8(&bytes).unwrap();
 
-        assert_eq!(body, "Header of type `logplex-drain-token` was missing");
+        config
+            .with_captured_sentry_events_async("real_token", |_, config| async move {
+                let mut request = Request::post("/").header(&LOGPLEX_DRAIN_TOKEN, "real_token");
+                if let Some(content_type) = content_type {
+                    request = request.header("content-type", content_type);
+                }
+
+                let response = build_app(config.clone())
+                    .oneshot(request.body(Body::from("some text")).unwrap())
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), expected);
+                assert_eq!(
+                    config
+                        .counters
+                        .get("unsupported_content_type_requests_total"),
+                    u64::from(expected != StatusCode::OK)
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_post_basic_auth() {
+        let _ = initialize_tracing();
+        let mut config = Config::default();
+        config.basic_auth_mappings.insert(
+            "user".into(),
+            BasicAuthMapping {
+                password: "pass".into(),
+                logplex_token: "real_token".into(),
+            },
+        );
+
+        config
+            .with_captured_sentry_events_async("real_token", |_, config| async move {
+                let app = build_app(config.clone());
+                let response = app
+                    .oneshot(
+                        Request::post("/")
+                            // user:pass
+                            .header("authorization", "Basic dXNlcjpwYXNz")
+                            .body(Body::from("some text"))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), StatusCode::OK);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_post_basic_auth_wrong_credentials() {
+        let _ = initialize_tracing();
+        let mut config = Config::default();
+        config.basic_auth_mappings.insert(
+            "user".into(),
+            BasicAuthMapping {
+                password: "other_pass".into(),
+                logplex_token: "real_token".into(),
+            },
+        );
+
+        config
+            .with_captured_sentry_events_async("real_token", |_, config| async move {
+                let app = build_app(config.clone());
+                let response = app
+                    .oneshot(
+                        Request::post("/")
+                            // user:pass
+                            .header("authorization", "Basic dXNlcjpwYXNz")
+                            .body(Body::from("some text"))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_post_disabled_destination() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0
+            ";
+
+        let test_sentry_transport = config
+            .clone()
+            .with_captured_sentry_transport_and_settings_async(
+                "real_token",
+                "enabled=false".parse().unwrap(),
+                |destination, config| async move {
+                    let response = build_app(config.clone())
+                        .oneshot(
+                            Request::post("/")
+                                .header(&LOGPLEX_DRAIN_TOKEN, "real_token")
+                                .body(Body::from(input))
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap();
+
+                    assert_eq!(response.status(), StatusCode::OK);
+                    assert_eq!(destination.counters.get("batches_muted"), 1);
+                },
+            )
+            .await;
+
+        // wait for async tasks to finish
+        config.shutdown().await;
+
+        assert!(test_sentry_transport.fetch_and_clear_envelopes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_batches_are_reported_once() {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com \
+            request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
+            fwd=\"204.204.204.204\" dyno=web.1 connect=0ms service=30000ms \
+            status=503 bytes=0 protocol=https\
+            ";
+
+        let test_sentry_transport = config
+            .clone()
+            .with_captured_sentry_transport_async("real_token", |destination, config| async move {
+                for _ in 0..2 {
+                    let response = build_app(config.clone())
+                        .oneshot(
+                            Request::post("/")
+                                .header(&LOGPLEX_DRAIN_TOKEN, "real_token")
+                                .body(Body::from(input))
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap();
+
+                    assert_eq!(response.status(), StatusCode::OK);
+                }
+
+                assert_eq!(destination.counters.get("duplicate_batches"), 1);
+            })
+            .await;
+
+        // wait for async tasks to finish
+        config.shutdown().await;
+
+        let events: Vec<_> = test_sentry_transport
+            .fetch_and_clear_envelopes()
+            .iter()
+            .filter_map(|envelope| envelope.event().cloned())
+            .collect();
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test_case("1", 0; "matching")]
+    #[test_case("2", 1; "diverging")]
+    #[tokio::test]
+    async fn test_msg_count_mismatches(msg_count: &str, expected: u64) {
+        let _ = initialize_tracing();
+        let config = Config::default();
+
+        let input = "
+            111 <158>1 2022-12-05T08:59:21.850424+00:00 host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com \
+            request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
+            fwd=\"204.204.204.204\" dyno=web.1 connect=0ms service=30000ms \
+            status=503 bytes=0 protocol=https\
+            ";
+
+        config
+            .clone()
+            .with_captured_sentry_transport_async("real_token", |destination, config| async move {
+                let response = build_app(config.clone())
+                    .oneshot(
+                        Request::post("/")
+                            .header(&LOGPLEX_DRAIN_TOKEN, "real_token")
+                            .header(&LOGPLEX_FRAME_ID, "09C557EAFCFB6CF2740EE62F62971098")
+                            .header(&LOGPLEX_MSG_COUNT, msg_count)
+                            .body(Body::from(input))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+
+                // wait for async tasks to finish
+                config.shutdown().await;
+
+                assert_eq!(destination.counters.get("msg_count_mismatches"), expected);
+            })
+            .await;
+    }
+
+    #[test_case("1", None; "matching")]
+    #[test_case("3", Some(2.0); "unparsed lines")]
+    #[tokio::test]
+    async fn test_lines_unparsed_metric(msg_count: &str, expected: Option<f64>) {
+        let _ = initialize_tracing();
+
+        let input = format!(
+            "111 <190>1 {} host app web.1 - Started GET /some/path",
+            Utc::now().to_rfc3339(),
+        );
+
+        let measurements = Config::default()
+            .with_captured_measurements_async(
+                "real_token",
+                DestinationSettings::default(),
+                |_destination, config| async move {
+                    let response = build_app(config.clone())
+                        .oneshot(
+                            Request::post("/")
+                                .header(&LOGPLEX_DRAIN_TOKEN, "real_token")
+                                .header(&LOGPLEX_MSG_COUNT, msg_count)
+                                .body(Body::from(input))
+                                .unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                    assert_eq!(response.status(), StatusCode::OK);
+
+                    config.shutdown().await;
+                },
+            )
+            .await;
+
+        let unparsed: Vec<_> = measurements
+            .iter()
+            .filter(|m| m.name == "lines_unparsed_total")
+            .collect();
+        assert_eq!(unparsed.first().map(|m| m.value), expected);
+        assert!(unparsed
+            .iter()
+            .all(|m| m.kind == crate::librato::Kind::Counter));
     }
 
     #[tokio::test]
@@ -244,14 +953,178 @@ mod tests {
             .collect();
 
         assert_eq!(events.len(), 1);
+        let logentry = events[0].logentry.as_ref().unwrap();
+        assert_eq!(logentry.message, "request timeout on %s\n%s");
         assert_eq!(
-            events[0].message.as_ref().unwrap(),
-            "request timeout on /\n\
-             at=error code=H12 desc=\"Request timeout\" \
+            logentry.params,
+            vec![
+                "/",
+                "at=error code=H12 desc=\"Request timeout\" \
              method=GET path=/ host=myapp.herokuapp.com \
              request_id=8601b555-6a83-4c12-8269-97c8e32cdb22 \
              fwd=\"204.204.204.204\" dyno=web.1 connect=0ms \
              service=30000ms status=503 bytes=0 protocol=https"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_flushes_metrics_of_pending_processing() {
+        let _ = initialize_tracing();
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .with_status(200)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+
+        let mut config = Config::default();
+        let destination = Arc::new(Destination::new(
+            "real_token",
+            Arc::new(sentry::Client::from(sentry::ClientOptions::default())),
+            Some(crate::librato::Client::new(
+                "username",
+                "token",
+                config.new_waitgroup_ticket(),
+                server.url() + "/",
+            )),
+        ));
+        config
+            .destinations
+            .insert("real_token".to_owned(), destination.clone());
+        let config = Arc::new(config);
+
+        let input = format!(
+            "111 <158>1 {} host heroku router - \
+            at=error code=H12 desc=\"Request timeout\" method=GET \
+            path=/ host=myapp.herokuapp.com dyno=web.1 status=503 bytes=0",
+            Utc::now().to_rfc3339(),
         );
+
+        let response = build_app(config.clone())
+            .oneshot(
+                Request::post("/")
+                    .header(&LOGPLEX_DRAIN_TOKEN, "real_token")
+                    .body(Body::from(input))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // shutdown right away, while the batch might still be processed.
+        config.shutdown().await;
+
+        mock.assert_async().await;
+        assert_eq!(destination.librato_client.as_ref().unwrap().queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_end_to_end_scaling_metrics() {
+        let _ = initialize_tracing();
+
+        // every gauge & counter librato receives,
+        // as (name, source, value, measure_time).
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("POST", "/")
+            .match_request({
+                let received = received.clone();
+                move |request| {
+                    let body: serde_json::Value =
+                        serde_json::from_slice(request.body().unwrap()).unwrap();
+                    received.lock().unwrap().extend(
+                        ["gauges", "counters"]
+                            .iter()
+                            .flat_map(|kind| body[kind].as_array().into_iter().flatten())
+                            .map(|measurement| {
+                                (
+                                    measurement["name"].as_str().unwrap().to_owned(),
+                                    measurement["source"].as_str().unwrap().to_owned(),
+                                    measurement["value"].as_f64().unwrap(),
+                                    measurement["measure_time"].as_i64().unwrap(),
+                                )
+                            }),
+                    );
+                    true
+                }
+            })
+            .with_status(200)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+
+        let mut config = Config::default();
+        let destination = Destination::new(
+            "real_token",
+            Arc::new(sentry::Client::from(sentry::ClientOptions::default())),
+            Some(crate::librato::Client::new(
+                "username",
+                "token",
+                config.new_waitgroup_ticket(),
+                server.url() + "/",
+            )),
+        );
+        config
+            .destinations
+            .insert("real_token".to_owned(), Arc::new(destination));
+        let config = Arc::new(config);
+
+        let timestamp = Utc::now().fixed_offset();
+        let input = format!(
+            "111 <190>1 {} host app api - \
+            Scaled to web@2:Standard-1X worker@1:Performance-M \
+            by user heroku.hirefire.api@thermondo.de",
+            timestamp.to_rfc3339(),
+        );
+
+        let response = build_app(config.clone())
+            .oneshot(
+                Request::post("/")
+                    .header(&LOGPLEX_DRAIN_TOKEN, "real_token")
+                    .body(Body::from(input))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        config.shutdown().await;
+        mock.assert_async().await;
+
+        let expected = generate_librato_scaling_metrics(
+            &timestamp,
+            &[
+                ScalingEvent {
+                    proc: "web",
+                    count: 2,
+                    size: "Standard-1X",
+                },
+                ScalingEvent {
+                    proc: "worker",
+                    count: 1,
+                    size: "Performance-M",
+                },
+            ],
+            false,
+            DynoCountGrouping::default(),
+            MetricNameCase::default(),
+            Some("heroku.hirefire.api@thermondo.de"),
+        );
+        assert!(!expected.is_empty());
+        let received = received.lock().unwrap();
+        for measurement in expected {
+            assert!(
+                received.contains(&(
+                    measurement.name.clone(),
+                    measurement.source.clone(),
+                    measurement.value,
+                    measurement.measure_time.timestamp(),
+                )),
+                "missing {measurement:?} in {received:?}"
+            );
+        }
     }
 }
@@ -0,0 +1,50 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+/// simple named counters for the internal observability of this service.
+/// The current values can be seen on the `/stats` endpoint.
+#[derive(Debug, Default)]
+pub(crate) struct Counters(Mutex<BTreeMap<&'static str, u64>>);
+
+impl Counters {
+    pub(crate) fn increment(&self, name: &'static str) {
+        self.add(name, 1);
+    }
+
+    pub(crate) fn add(&self, name: &'static str, value: u64) {
+        *self.0.lock().unwrap().entry(name).or_default() += value;
+    }
+
+    pub(crate) fn get(&self, name: &'static str) -> u64 {
+        self.0
+            .lock()
+            .unwrap()
+            .get(name)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        assert_eq!(counters.get("something"), 0);
+
+        counters.increment("something");
+        counters.add("something", 2);
+        counters.increment("other");
+
+        assert_eq!(counters.get("something"), 3);
+        assert_eq!(
+            counters.snapshot(),
+            BTreeMap::from_iter([("other", 1), ("something", 3)])
+        );
+    }
+}
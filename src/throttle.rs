@@ -0,0 +1,50 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// allows an action at most once per interval,
+/// for example to prevent flooding the logs.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// returns `true` when the action is allowed now.
+    pub(crate) fn allow(&self) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        match *last {
+            Some(last) if now.duration_since(last) < self.interval => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let throttle = Throttle::new(Duration::from_millis(10));
+
+        assert!(throttle.allow());
+        assert!(!throttle.allow());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(throttle.allow());
+    }
+}